@@ -0,0 +1,35 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use crate::tls::{config_dir, Identity, CERT_FILE, KEY_FILE};
+
+/// Directory holding the credential `host` issued when this client
+/// paired with it.
+fn dir(host: &str) -> Result<PathBuf> {
+    Ok(config_dir()?.join("credentials").join(host))
+}
+
+/// The credential to present to `host`, if this client paired with it.
+pub fn load(host: &str) -> Result<Option<Identity>> {
+    let dir = dir(host)?;
+    let cert = dir.join(CERT_FILE);
+    if !cert.exists() {
+        return Ok(None);
+    }
+    Identity::load(&cert, &dir.join(KEY_FILE)).map(Some)
+}
+
+/// Keep the credential `host` issued, replacing an earlier one.
+pub fn store(host: &str, identity: &Identity) -> Result<()> {
+    let dir = dir(host)?;
+    for file in [CERT_FILE, KEY_FILE] {
+        match fs::remove_file(dir.join(file)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    identity.save(&dir.join(CERT_FILE), &dir.join(KEY_FILE))
+}
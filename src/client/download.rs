@@ -0,0 +1,167 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use tokio::fs::{File, OpenOptions};
+use async_compression::tokio::bufread::ZstdDecoder;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+
+use crate::model::FileInfo;
+
+/// Appended to the name of a file while it is being downloaded
+const PART_SUFFIX: &str = ".part";
+/// Bytes read from the stream at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Where `name` is kept in `dir` until it is complete and verified
+pub fn part_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}{}", name, PART_SUFFIX))
+}
+
+/// Offset to fetch `name` from, past whatever an earlier attempt left
+/// in `dir`.
+pub fn resume_offset(dir: &Path, name: &str) -> u64 {
+    fs::metadata(part_path(dir, name)).map_or(0, |m| m.len())
+}
+
+/// Write a fetched range to the part file of its name in `dir` as it
+/// arrives, calling `progress` with the percentage of the file held.
+/// Returns whether the file is complete, in which case it was checked
+/// against the server's hash and given its name. An interrupted
+/// download keeps its part file so it can be resumed. The range arrives
+/// as a single zstd stream if `compressed`.
+pub async fn download<R, F>(dir: &Path, stream: R, info: FileInfo, compressed: bool, mut progress: F) -> Result<bool>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    F: FnMut(u64),
+{
+    let FileInfo { name, size, hash, offset, length, .. } = info;
+    // the name comes from the server, keep it out of other directories
+    if Path::new(&name).file_name() != Some(Path::new(&name).as_os_str()) {
+        bail!("not a plain file name");
+    }
+    if dir.join(&name).exists() {
+        bail!("{} already exists", name);
+    }
+    let part = part_path(dir, &name);
+    let mut file = open_part(&part, offset).await?;
+    let mut stream: Box<dyn AsyncRead + Send + Unpin> = match compressed {
+        true => Box::new(ZstdDecoder::new(BufReader::new(stream))),
+        false => Box::new(stream),
+    };
+    let mut received = 0;
+    let mut shown = None;
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        file.write_all(&chunk[..n]).await?;
+        received += n as u64;
+        let percent = (offset + received) * 100 / size.max(1);
+        if shown != Some(percent) {
+            shown = Some(percent);
+            progress(percent);
+        }
+    }
+    file.flush().await?;
+    if received != length {
+        bail!("received {} of {} bytes, fetch again to resume", received, length);
+    }
+    if offset + length < size {
+        return Ok(false);
+    }
+    finish(dir, &name, hash).await?;
+    Ok(true)
+}
+
+/// Open the part file to write a range starting at `offset` to. A range
+/// from the start replaces an old part file, any other continues it.
+async fn open_part(part: &Path, offset: u64) -> Result<File> {
+    if offset == 0 {
+        return Ok(File::create(part).await?);
+    }
+    let file = OpenOptions::new()
+        .append(true)
+        .open(part)
+        .await
+        .map_err(|e| anyhow!("can't resume {}: {}", part.display(), e))?;
+    let held = file.metadata().await?.len();
+    if held != offset {
+        bail!("server sent bytes from {}, but {} bytes are held", offset, held);
+    }
+    Ok(file)
+}
+
+/// Check the part file of `name` in `dir` against `hash` and rename it
+/// to `name`. A part file that doesn't match is removed, it was pieced
+/// together from different versions of the file.
+async fn finish(dir: &Path, name: &str, hash: [u8; 32]) -> Result<()> {
+    let part = part_path(dir, name);
+    let path = part.clone();
+    let held = tokio::task::spawn_blocking(move || -> Result<[u8; 32]> {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(fs::File::open(path)?)?;
+        Ok(*hasher.finalize().as_bytes())
+    })
+    .await??;
+    if held != hash {
+        tokio::fs::remove_file(&part).await?;
+        bail!("{} does not match the server's checksum, fetch it again", name);
+    }
+    tokio::fs::rename(&part, dir.join(name)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_finish() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let data = b"not really a jpeg";
+        fs::write(part_path(dir.path(), "a.jpg"), &data[..4])?;
+        assert_eq!(resume_offset(dir.path(), "a.jpg"), 4);
+        assert_eq!(resume_offset(dir.path(), "b.jpg"), 0);
+        assert!(open_part(&part_path(dir.path(), "a.jpg"), 3).await.is_err());
+
+        let mut file = open_part(&part_path(dir.path(), "a.jpg"), 4).await?;
+        file.write_all(&data[4..]).await?;
+        file.flush().await?;
+        finish(dir.path(), "a.jpg", *blake3::hash(data).as_bytes()).await?;
+        assert_eq!(fs::read(dir.path().join("a.jpg"))?, data);
+        assert_eq!(resume_offset(dir.path(), "a.jpg"), 0);
+
+        fs::write(part_path(dir.path(), "c.jpg"), b"stale")?;
+        assert!(finish(dir.path(), "c.jpg", *blake3::hash(data).as_bytes()).await.is_err());
+        assert!(!part_path(dir.path(), "c.jpg").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_compressed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let data = b"BM uncompressed pixels ".repeat(1000);
+        let info = FileInfo {
+            id: 1,
+            name: "a.bmp".to_string(),
+            size: data.len() as u64,
+            hash: *blake3::hash(&data).as_bytes(),
+            offset: 0,
+            length: data.len() as u64,
+        };
+        let stream = std::io::Cursor::new(zstd::encode_all(&data[..], 3)?);
+        assert!(download(dir.path(), stream, info.clone(), true, |_| {}).await?);
+        assert_eq!(fs::read(dir.path().join("a.bmp"))?, data);
+
+        // cut short, the part file keeps what arrived for resuming
+        let info = FileInfo { name: "b.bmp".to_string(), ..info };
+        let cut = zstd::encode_all(&data[..], 3)?;
+        let stream = std::io::Cursor::new(cut[..cut.len() / 2].to_vec());
+        assert!(download(dir.path(), stream, info, true, |_| {}).await.is_err());
+        assert!(resume_offset(dir.path(), "b.bmp") < data.len() as u64);
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+
+use crate::tls::{config_dir, fingerprint, provider};
+
+/// Server certificate fingerprints pinned the first time the client
+/// connected to a host, stored one `host fingerprint` pair per line.
+#[derive(Debug)]
+pub struct KnownHosts {
+    path: PathBuf,
+    hosts: BTreeMap<String, String>,
+}
+
+impl KnownHosts {
+    /// `known_hosts` in the config directory
+    pub fn default_path() -> Result<PathBuf> {
+        Ok(config_dir()?.join("known_hosts"))
+    }
+    /// Read the store at `path`. A missing file is an empty store.
+    pub fn open(path: &Path) -> Result<Self> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => bail!("Known Hosts Error: read {}: {}", path.display(), e),
+        };
+        let mut hosts = BTreeMap::new();
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            let (host, fingerprint) = line
+                .split_once(' ')
+                .ok_or(anyhow!("Known Hosts Error: bad line in {}: {}", path.display(), line))?;
+            hosts.insert(host.to_string(), fingerprint.trim().to_string());
+        }
+        let path = path.to_path_buf();
+        Ok(Self { path, hosts })
+    }
+    pub fn get(&self, host: &str) -> Option<&str> {
+        self.hosts.get(host).map(|f| f.as_str())
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.hosts.iter().map(|(h, f)| (h.as_str(), f.as_str()))
+    }
+    /// Remember `fingerprint` as the certificate of `host`
+    pub fn pin(&mut self, host: &str, fingerprint: &str) -> Result<()> {
+        self.hosts.insert(host.to_string(), fingerprint.to_string());
+        self.save()
+    }
+    /// Drop the pin for `host`. Returns false if there was none.
+    pub fn forget(&mut self, host: &str) -> Result<bool> {
+        let found = self.hosts.remove(host).is_some();
+        if found {
+            self.save()?;
+        }
+        Ok(found)
+    }
+    fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let text: String = self
+            .iter()
+            .map(|(host, fingerprint)| format!("{} {}\n", host, fingerprint))
+            .collect();
+        fs::write(&self.path, text)
+            .map_err(|e| anyhow!("Known Hosts Error: write {}: {}", self.path.display(), e))
+    }
+}
+
+/// Trust on first use: accepts the certificate pinned for a host, or
+/// any certificate if none is pinned yet. The fingerprint presented is
+/// kept so it can be pinned, or reported, once the handshake is over.
+#[derive(Debug)]
+pub struct TofuVerifier {
+    pinned: Option<String>,
+    presented: Arc<Mutex<Option<String>>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl TofuVerifier {
+    pub fn new(pinned: Option<String>, presented: Arc<Mutex<Option<String>>>) -> Self {
+        let provider = provider();
+        Self { pinned, presented, provider }
+    }
+}
+
+impl ServerCertVerifier for TofuVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let presented = fingerprint(end_entity);
+        *self.presented.lock().unwrap() = Some(presented.clone());
+        match &self.pinned {
+            Some(pinned) if *pinned != presented => Err(rustls::Error::General(
+                "server certificate does not match the pinned fingerprint".to_string(),
+            )),
+            _ => Ok(ServerCertVerified::assertion()),
+        }
+    }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pin_and_forget() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("known_hosts");
+        let mut hosts = KnownHosts::open(&path)?;
+        assert!(hosts.get("frame:4433").is_none());
+        hosts.pin("frame:4433", "aa:bb")?;
+        hosts.pin("tablet:4433", "cc:dd")?;
+
+        let mut hosts = KnownHosts::open(&path)?;
+        assert_eq!(hosts.get("frame:4433"), Some("aa:bb"));
+        assert!(hosts.forget("frame:4433")?);
+        assert!(!hosts.forget("frame:4433")?);
+
+        let hosts = KnownHosts::open(&path)?;
+        assert_eq!(hosts.iter().collect::<Vec<_>>(), vec![("tablet:4433", "cc:dd")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tofu_verifier() -> Result<()> {
+        let identity = crate::tls::Identity::generate_self_signed()?;
+        let cert = crate::tls::parse_certificates(&identity.cert)?.remove(0);
+        let name = ServerName::try_from("localhost")?;
+        let verify = |pinned: Option<&str>| {
+            let presented = Arc::new(Mutex::new(None));
+            let verifier = TofuVerifier::new(pinned.map(String::from), presented.clone());
+            let verified = verifier.verify_server_cert(&cert, &[], &name, &[], UnixTime::now());
+            let presented = presented.lock().unwrap().clone();
+            (verified.is_ok(), presented)
+        };
+        // first use records what was presented
+        let (ok, presented) = verify(None);
+        assert!(ok);
+        assert_eq!(presented, Some(fingerprint(&cert)));
+        assert!(verify(presented.as_deref()).0);
+        assert!(!verify(Some("aa:bb")).0);
+        Ok(())
+    }
+}
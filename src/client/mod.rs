@@ -1,63 +1,387 @@
 mod term_view;
+mod service;
 mod quic_service;
+mod tcp_service;
+mod unix_service;
+mod known_hosts;
+mod credentials;
+mod download;
+mod scrub;
 
 use std::error::Error;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{self, TryRecvError};
+use anyhow::{anyhow, bail};
+use raw_tty::TtyModeGuard;
 use terminal_keycode::KeyCode;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::task::JoinHandle;
 use tracing::debug;
 
-use crate::model::Request;
-use crate::client::quic_service::QuicService;
+use crate::model::{
+    read_frame, write_frame, PairReply, PairRequest, Request, Response, Role, ServerCommand, Status, Wire,
+};
+use crate::discovery::{discover, Found, DISCOVERY_PORT, DISCOVERY_WAIT};
+use crate::tls::{read_pem, Identity};
+use crate::transport::{Reader, TransportKind, Writer};
+use crate::client::known_hosts::KnownHosts;
+use crate::client::scrub::Scrub;
+use crate::client::service::{Connected, Service, Trust};
 use crate::client::term_view::{TermView, TermInput};
 
 // TODO organize / cleanup the client
 
-/// Runs a client
-pub async fn run_client(host: String) -> Result<(), Box<dyn Error>> {
-    let client = QuicService::new(host)?;
+/// Runs a client. With a `ca` the server must present a certificate
+/// it issued for `server_name`; without one the server's certificate
+/// is pinned on first connect. `identity` is presented to servers that
+/// only admit known clients, by default the one issued when pairing.
+/// A lost connection is retried until it is back or the client quits.
+pub async fn run_client(
+    host: String,
+    transport: TransportKind,
+    ca: Option<&Path>,
+    server_name: Option<&str>,
+    identity: Option<Identity>,
+) -> Result<(), Box<dyn Error>> {
+    let mut client = service(host, transport, ca, server_name, identity)?;
     // connect to server, get receive and send channels to server
-    let (receive, mut send) = client.connect().await?;
+    let connected = client.connect().await?;
+    // put the terminal back as it was, however the client ends
+    let _terminal = TtyModeGuard::new(std::io::stdin().as_raw_fd())?;
     let view = TermView::new()?;
-    // spawn a task that copies responses from the server to stdout
-    let _handle_out = view.stdout_task(receive);
+    let on_display = view.on_display();
+    let position = view.position();
+    // spawn a task that writes responses, events and our own status to stdout
+    let (_handle_out, updates) = view.stdout_task();
+    let mut send = connected.send;
+    let mut wire = connected.wire;
+    let mut connection = updates.attach(connected.receive, connected.streams, connected.wire);
+    let mut scrub = connected.datagrams.map(|d| Scrub::new(d, position.clone(), wire));
     // track if we are exiting
     let should_exit = Arc::new(Mutex::new(false));
-    let (tx, rx) = mpsc::channel::<KeyCode>();
+    let (tx, mut rx) = mpsc::unbounded_channel::<KeyCode>();
+    // id for the next request, echoed back by the server
+    let mut request_id: u64 = 0;
 
     let input = TermInput::new()?;
     let handle = input.stdin_task(should_exit.clone(), tx);
 
-    // handle incoming keycodes
-    loop {
-        if *should_exit.lock().expect("lock mutex") {
-            break;
-        }
-        match rx.try_recv() {
-            Ok(keycode) => {
-                match keycode {
-                    KeyCode::Char('q') |
-		    KeyCode::Escape | 
-		    KeyCode::CtrlC => *should_exit.lock().expect("lock mutex") = true,
-		    // If not a Client command send Request to Server
-                    _ => {
-			if let Some(request) = Request::new(keycode) {
-			    let bytes = request.to_bytes()?;
-			    send.send(bytes.into()).await?;
-			    
-			} else {
-			    debug!("keycode does not represent a server command");
-			}
-                    }
-                };
-            }
-            Err(TryRecvError::Empty) => {}
-            Err(TryRecvError::Disconnected) => panic!("Channel disconnected"),
-        }
+    'session: loop {
+	// the display may have moved on while we were away
+	let ended = session(&mut send, wire, &mut scrub, &mut rx, &mut connection, &mut request_id, &on_display).await?;
+	if let Ended::Exit = ended {
+	    break;
+	}
+	updates.status("connection lost, reconnecting");
+	let reconnect = client.reconnect(|e, delay| debug!("reconnect failed, retrying in {:?}: {}", delay, e));
+	tokio::pin!(reconnect);
+	let connected = loop {
+	    tokio::select! {
+		result = &mut reconnect => break result?,
+		keycode = rx.recv() => match keycode {
+		    Some(keycode) if !is_exit(&keycode) => updates.status("offline, key ignored"),
+		    _ => break 'session,
+		},
+	    }
+	};
+	updates.status("reconnected");
+	send = connected.send;
+	wire = connected.wire;
+	connection = updates.attach(connected.receive, connected.streams, connected.wire);
+	scrub = connected.datagrams.map(|d| Scrub::new(d, position.clone(), wire));
     }
 
+    *should_exit.lock().expect("lock mutex") = true;
     handle.await.expect("join mpsc handle")?;
     
     Ok(())
 }
 
+/// How a session on one connection ended
+enum Ended {
+    Exit,
+    Lost,
+}
+
+/// Ask for the state of the display, then send a request for each key
+/// pressed until the client quits or `connection` is lost. Arrow keys go
+/// to `scrub` instead, if the server agreed to datagrams.
+async fn session(
+    send: &mut Writer,
+    wire: Wire,
+    scrub: &mut Option<Scrub>,
+    keys: &mut UnboundedReceiver<KeyCode>,
+    connection: &mut JoinHandle<anyhow::Result<()>>,
+    request_id: &mut u64,
+    on_display: &Mutex<Option<String>>,
+) -> anyhow::Result<Ended> {
+    let mut request = Some(Request::status(*request_id));
+    loop {
+	if let Some(request) = request.take() {
+	    *request_id += 1;
+	    if write_frame(send, &request.to_bytes(wire)?).await.is_err() {
+		return Ok(Ended::Lost);
+	    }
+	}
+	let keycode = tokio::select! {
+	    _ = &mut *connection => return Ok(Ended::Lost),
+	    keycode = keys.recv() => match keycode {
+		Some(keycode) => keycode,
+		None => return Ok(Ended::Exit),
+	    },
+	};
+	if is_exit(&keycode) {
+	    return Ok(Ended::Exit);
+	}
+	if let Some(scrub) = scrub {
+	    match scrub.key(&keycode) {
+		Ok(true) => continue,
+		Ok(false) => {}
+		Err(e) => {
+		    debug!("intent not sent: {}", e);
+		    return Ok(Ended::Lost);
+		}
+	    }
+	}
+	// If not a Client command send Request to Server
+	request = key_request(keycode, *request_id, on_display);
+	if request.is_none() {
+	    debug!("keycode does not represent a server command");
+	}
+    }
+}
+
+/// Whether `keycode` quits the client
+fn is_exit(keycode: &KeyCode) -> bool {
+    matches!(keycode, KeyCode::Char('q') | KeyCode::Escape | KeyCode::CtrlC)
+}
+
+/// The request with `id` that `keycode` stands for, if any
+fn key_request(keycode: KeyCode, id: u64, on_display: &Mutex<Option<String>>) -> Option<Request> {
+    let request = Request::new(id, keycode)?;
+    // pick up where an earlier fetch of the image left off
+    if let ServerCommand::Fetch { .. } = request.command() {
+	let name = on_display.lock().expect("lock mutex").clone();
+	let offset = name.map_or(0, |n| download::resume_offset(Path::new("."), &n));
+	return Some(Request::fetch(id, offset, None));
+    }
+    Some(request)
+}
+
+/// Set up a connection to `host` as `run_client` describes.
+fn service(
+    host: String,
+    transport: TransportKind,
+    ca: Option<&Path>,
+    server_name: Option<&str>,
+    identity: Option<Identity>,
+) -> anyhow::Result<Service> {
+    let trust = match ca {
+	Some(ca) => Trust::Ca(read_pem(ca)?),
+	None => Trust::FirstUse(KnownHosts::open(&KnownHosts::default_path()?)?),
+    };
+    let identity = match identity {
+	Some(identity) => Some(identity),
+	None => credentials::load(&host)?,
+    };
+    Service::new(transport, host, trust, server_name, identity.as_ref())
+}
+
+/// Add `file` to the image directory of the server at `host`, and
+/// `show` it. Connects as `run_client` does.
+pub async fn upload_file(
+    host: String,
+    transport: TransportKind,
+    ca: Option<&Path>,
+    server_name: Option<&str>,
+    identity: Option<Identity>,
+    file: &Path,
+    show: bool,
+) -> anyhow::Result<()> {
+    let name = file
+	.file_name()
+	.ok_or(anyhow!("{} is not a file", file.display()))?
+	.to_string_lossy()
+	.into_owned();
+    let source = File::open(file).await?;
+    let size = source.metadata().await?.len();
+    let mut client = service(host, transport, ca, server_name, identity)?;
+    let Connected { mut receive, send, wire, .. } = client.connect().await?;
+
+    let request = Request::upload(0, &name, size, show);
+    send_data(&mut receive, send, wire, &request, source, size)
+	.await
+	.map_err(|e| anyhow!("upload refused: {}", e))?;
+    println!("uploaded {} ({} bytes)", name, size);
+    Ok(())
+}
+
+/// Show `file` on the display of the server at `host` until enter is
+/// pressed, without the server keeping it. Connects as `run_client`
+/// does.
+pub async fn cast_file(
+    host: String,
+    transport: TransportKind,
+    ca: Option<&Path>,
+    server_name: Option<&str>,
+    identity: Option<Identity>,
+    file: &Path,
+) -> anyhow::Result<()> {
+    let source = File::open(file).await?;
+    let size = source.metadata().await?.len();
+    let mut client = service(host, transport, ca, server_name, identity)?;
+    let Connected { mut receive, send, wire, .. } = client.connect().await?;
+
+    let mut send = send_data(&mut receive, send, wire, &Request::cast(0, size), source, size)
+	.await
+	.map_err(|e| anyhow!("cast refused: {}", e))?;
+    println!("casting {}, press enter to stop", file.display());
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    tokio::select! {
+	_ = stdin.next_line() => {}
+	_ = tokio::signal::ctrl_c() => {}
+    }
+    write_frame(&mut send, &Request::end_cast(1).to_bytes(wire)?).await?;
+    read_response(&mut receive, wire).await?;
+    Ok(())
+}
+
+/// Send `request` followed by the `size` bytes of `source`, and wait
+/// for the server to take them. Returns the send half for further
+/// requests, or the server's reason for refusing.
+async fn send_data(
+    receive: &mut Reader,
+    mut send: Writer,
+    wire: Wire,
+    request: &Request,
+    source: File,
+    size: u64,
+) -> anyhow::Result<Writer> {
+    write_frame(&mut send, &request.to_bytes(wire)?).await?;
+    // the server may refuse before it has everything, so send while
+    // waiting for its answer
+    let copy = tokio::spawn(async move {
+	tokio::io::copy(&mut source.take(size), &mut send).await?;
+	Ok::<_, anyhow::Error>(send)
+    });
+    let response = read_response(receive, wire).await?;
+    if response.status() != Status::Success {
+	copy.abort();
+	bail!("{}", response.message());
+    }
+    copy.await?
+}
+
+async fn read_response(receive: &mut Reader, wire: Wire) -> anyhow::Result<Response> {
+    let bytes = read_frame(receive)
+	.await?
+	.ok_or(anyhow!("server closed the stream"))?;
+    Response::from_bytes(bytes, wire)
+}
+
+/// Print every pinned server and its certificate fingerprint
+pub fn list_hosts() -> anyhow::Result<()> {
+    let known_hosts = KnownHosts::open(&KnownHosts::default_path()?)?;
+    for (host, fingerprint) in known_hosts.iter() {
+	println!("{} {}", host, fingerprint);
+    }
+    Ok(())
+}
+
+/// Forget the certificate pinned for `host`, so the next connection
+/// pins whatever it presents.
+pub fn forget_host(host: &str) -> anyhow::Result<()> {
+    let mut known_hosts = KnownHosts::open(&KnownHosts::default_path()?)?;
+    if !known_hosts.forget(host)? {
+	anyhow::bail!("no certificate pinned for {}", host);
+    }
+    println!("forgot {}", host);
+    Ok(())
+}
+
+/// Look for servers answering a broadcast to `broadcast`, list them
+/// and return the address and transport of the one to connect to,
+/// asking which if there are several.
+pub async fn discover_server(broadcast: &str) -> anyhow::Result<(String, TransportKind)> {
+    let target = SocketAddr::new(
+	broadcast.parse().map_err(|e| anyhow!("Discovery Error: {} is not an address: {}", broadcast, e))?,
+	DISCOVERY_PORT,
+    );
+    let found = discover(target, DISCOVERY_WAIT).await?;
+    if found.is_empty() {
+	bail!("no servers answered on {}", target);
+    }
+    let known_hosts = KnownHosts::open(&KnownHosts::default_path()?)?;
+    for (n, Found { address, announcement }) in found.iter().enumerate() {
+	// anyone on the network may answer, the pin is what to trust
+	let pin = match known_hosts.get(address) {
+	    Some(pinned) if pinned == announcement.fingerprint => "pinned",
+	    Some(_) => "CERTIFICATE CHANGED",
+	    None => "new",
+	};
+	println!("{} {} {} {} {} {}", n + 1, announcement.name, address,
+		 announcement.transport, announcement.fingerprint, pin);
+    }
+    let chosen = if found.len() == 1 {
+	&found[0]
+    } else {
+	println!("connect to which server?");
+	let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+	let line = stdin.next_line().await?.ok_or(anyhow!("no server chosen"))?;
+	line.trim()
+	    .parse::<usize>()
+	    .ok()
+	    .and_then(|n| found.get(n.checked_sub(1)?))
+	    .ok_or(anyhow!("no server {}", line.trim()))?
+    };
+    let transport = chosen.announcement.transport.parse()?;
+    Ok((chosen.address.clone(), transport))
+}
+
+/// Pair with the server at `host`: send the PIN it shows and keep the
+/// credential it issues for later connections. The client is listed
+/// on the server under `name`, by default this machine's host name, and
+/// asks to be given `role`.
+pub async fn pair_client(
+    host: String,
+    transport: TransportKind,
+    server_name: Option<&str>,
+    name: Option<String>,
+    role: Role,
+) -> anyhow::Result<()> {
+    let trust = Trust::FirstUse(KnownHosts::open(&KnownHosts::default_path()?)?);
+    let client = Service::new(transport, host.clone(), trust, server_name, None)?;
+    let (mut stream, wire) = client.pair().await?;
+    let name = name
+	.or_else(|| hostname::get().ok().and_then(|h| h.into_string().ok()))
+	.unwrap_or_else(|| "client".to_string());
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+	let bytes = read_frame(&mut stream)
+	    .await?
+	    .ok_or(anyhow!("server closed the pairing stream"))?;
+	match PairReply::from_bytes(bytes, wire)? {
+	    PairReply::PinShown(seconds) => {
+		println!("enter the PIN shown on the display within {} seconds:", seconds);
+	    }
+	    PairReply::Retry(reason) => println!("{}, try again:", reason),
+	    PairReply::Refused(reason) => bail!("pairing refused: {}", reason),
+	    PairReply::Paired { cert, key, role: granted } => {
+		let identity = Identity { cert, key };
+		credentials::store(&host, &identity)?;
+		println!("paired with {} as {} ({}), granted the {} role", host, name, identity.fingerprint()?, granted);
+		if granted != role {
+		    println!("the server allows no more than {}, rather than the {} asked for", granted, role);
+		}
+		return Ok(());
+	    }
+	}
+	let pin = stdin.next_line().await?.ok_or(anyhow!("no PIN entered"))?;
+	let request = PairRequest { pin: pin.trim().to_string(), name: name.clone(), role };
+	write_frame(&mut stream, &request.to_bytes(wire)?).await?;
+    }
+}
@@ -1,39 +1,126 @@
-use anyhow::Result;
-use s2n_quic::{
-    client::Connect,
-    stream::{ReceiveStream, SendStream}, Client
-};
-use std::net::SocketAddr;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use rustls::ClientConfig;
+use s2n_quic::{client::Connect, provider::tls, Client};
+use std::time::Duration;
 use tracing::debug;
 
-/// NOTE: this certificate is to be used for demonstration purposes only!
-pub static CERT_PEM: &str = include_str!(concat!("../../tls/cert.pem"));
+use crate::transport::{address, datagram_endpoint, ClientTransport, Connection};
 
+/// Time to reach one address before trying the next
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Client side of Quic connection
 pub struct QuicService {
     client: Client,
-    connect: Connect,
+    host: String,
+    server_name: String,
 }
 
 impl QuicService {
-    pub fn new(host: String) -> Result<QuicService> {
-        debug! {"\n{}", CERT_PEM};
-        let client = Client::builder()
-            .with_tls(CERT_PEM)?
-            .with_io("0.0.0.0:0")?
-            .start()?;
-
-        let remote: SocketAddr = host.parse()?;
-        let connect = Connect::new(remote).with_server_name("localhost");
-        let q = QuicService { client, connect };
-        Ok(q)
+    /// Reach `host` with TLS set up by `config`, sending `server_name`.
+    pub fn new(host: &str, server_name: &str, config: ClientConfig) -> Result<QuicService> {
+        let tls = tls::rustls::Client::from(config);
+        // an IPv6 socket reaches IPv4 addresses too, where there is IPv6
+        let client = start(tls.clone(), "[::]:0").or_else(|_| start(tls, "0.0.0.0:0"))?;
+        Ok(QuicService {
+            client,
+            host: host.to_string(),
+            server_name: server_name.to_string(),
+        })
+    }
+}
+
+fn start(tls: tls::rustls::Client, bind: &str) -> Result<Client> {
+    Ok(Client::builder()
+        .with_tls(tls)?
+        .with_datagram(datagram_endpoint()?)?
+        .with_io(bind)?
+        .start()?)
+}
+
+#[async_trait]
+impl ClientTransport for QuicService {
+    /// Try each address `host` resolves to in turn. The host is looked
+    /// up again on every connect, in case it moved.
+    async fn connect(&mut self) -> Result<Connection> {
+        let addresses = address::resolve(&self.host).await?;
+        let last = addresses.len() - 1;
+        for (i, remote) in addresses.into_iter().enumerate() {
+            let connect = Connect::new(remote).with_server_name(self.server_name.as_str());
+            let attempt = self.client.connect(connect);
+            let mut connection = if i == last {
+                attempt.await?
+            } else {
+                match tokio::time::timeout(ATTEMPT_TIMEOUT, attempt).await {
+                    Ok(Ok(connection)) => connection,
+                    Ok(Err(e)) => {
+                        debug!("{} failed: {}", remote, e);
+                        continue;
+                    }
+                    Err(_) => {
+                        debug!("{} timed out", remote);
+                        continue;
+                    }
+                }
+            };
+
+            // ensure the connection doesn't time out with inactivity
+            connection.keep_alive(true)?;
+            return Ok(Connection::quic(connection));
+        }
+        Err(anyhow!("Connect Error: no address of {} to try", self.host))
     }
-    pub async fn connect(self) -> Result<(ReceiveStream, SendStream)> {
-        let mut connection = self.client.connect(self.connect).await?;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use s2n_quic::Server;
+
+    use crate::tls::{ca_verifier, client_config, server_config, Identity};
+
+    /// A server on `bind` that takes in connections and reports where
+    /// they came from
+    fn server(bind: &str, identity: &Identity) -> Result<tokio::sync::mpsc::UnboundedReceiver<std::net::SocketAddr>> {
+        let tls = tls::rustls::Server::from(server_config(identity, false)?);
+        let mut server = Server::builder().with_tls(tls)?.with_io(bind)?.start()?;
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            // kept open until the test ends
+            let mut open = Vec::new();
+            while let Some(connection) = server.accept().await {
+                tx.send(connection.remote_addr().expect("remote")).ok();
+                open.push(connection);
+            }
+        });
+        Ok(rx)
+    }
+
+    #[tokio::test]
+    async fn test_connect_by_name_and_literal() -> Result<()> {
+        let identity = Identity::generate_for(vec!["localhost".to_string()])?;
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")?.local_addr()?.port();
+        let mut v4 = server(&format!("127.0.0.1:{}", port), &identity)?;
+        let mut v6 = server(&format!("[::1]:{}", port), &identity)?;
+        let config = || client_config(ca_verifier(&identity.cert).unwrap(), None);
 
-        // ensure the connection doesn't time out with inactivity
-        connection.keep_alive(true)?;
+        // the certificate is checked against the name in the address
+        let host = format!("localhost:{}", port);
+        let name = address::host_name(&host).expect("a name");
+        QuicService::new(&host, name, config()?)?.connect().await?;
+        let reached = tokio::select! {
+            Some(remote) = v4.recv() => remote,
+            Some(remote) = v6.recv() => remote,
+        };
+        assert!(reached.ip().is_loopback());
 
-        let stream = connection.open_bidirectional_stream().await?;
-        Ok(stream.split())
+        QuicService::new(&format!("[::1]:{}", port), "localhost", config()?)?.connect().await?;
+        assert!(v6.recv().await.expect("connected").is_ipv6());
+        QuicService::new(&format!("127.0.0.1:{}", port), "localhost", config()?)?.connect().await?;
+        let remote = v4.recv().await.expect("connected");
+        // over a dual-stack socket an IPv4 client may show up mapped
+        assert!(remote.is_ipv4() || remote.ip().to_canonical().is_ipv4());
+        Ok(())
     }
 }
@@ -0,0 +1,86 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use terminal_keycode::KeyCode;
+
+use crate::model::{Intent, Position, Wire};
+use crate::transport::Datagrams;
+
+/// How long after an arrow key the display is taken to be catching up.
+/// Keys pressed meanwhile move on from the image last asked for rather
+/// than from the one the server last showed.
+const SETTLE: Duration = Duration::from_secs(1);
+
+/// Sends arrow keys as datagrams naming the image to show, so that
+/// holding a key down never queues up behind the request stream
+pub struct Scrub {
+    datagrams: Arc<dyn Datagrams>,
+    position: Arc<Mutex<Option<Position>>>,
+    wire: Wire,
+    seq: u64,
+    // index last asked for, and when
+    sent: Option<(u64, Instant)>,
+}
+
+impl Scrub {
+    /// Send on `datagrams` packed as `wire`, counting from `position`
+    /// as the server reports it
+    pub fn new(datagrams: Arc<dyn Datagrams>, position: Arc<Mutex<Option<Position>>>, wire: Wire) -> Scrub {
+        Scrub {
+            datagrams,
+            position,
+            wire,
+            seq: 0,
+            sent: None,
+        }
+    }
+
+    /// Send the intent `keycode` stands for. Returns false if it is not
+    /// an arrow key or the position of the display isn't known yet, the
+    /// key is then up to the request stream.
+    pub fn key(&mut self, keycode: &KeyCode) -> Result<bool> {
+        let step = match keycode {
+            KeyCode::ArrowRight => 1,
+            KeyCode::ArrowLeft => -1,
+            _ => return Ok(false),
+        };
+        let Some(position) = *self.position.lock().unwrap() else {
+            return Ok(false);
+        };
+        let from = match self.sent {
+            Some((index, at)) if at.elapsed() < SETTLE => index,
+            _ => position.index,
+        };
+        let Some(index) = step_index(from, step, position.count) else {
+            return Ok(false);
+        };
+        self.seq += 1;
+        let intent = Intent { seq: self.seq, index };
+        self.datagrams.send(intent.to_bytes(self.wire)?.into())?;
+        self.sent = Some((index, Instant::now()));
+        Ok(true)
+    }
+}
+
+/// The index `step` images on from `index`, going round among `count`
+fn step_index(index: u64, step: i64, count: u64) -> Option<u64> {
+    let count = i64::try_from(count).ok().filter(|count| *count > 0)?;
+    let index = i64::try_from(index).ok()?;
+    Some((index + step).rem_euclid(count) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_step_index() {
+        assert_eq!(step_index(3, 1, 10), Some(4));
+        assert_eq!(step_index(9, 1, 10), Some(0));
+        assert_eq!(step_index(0, -1, 10), Some(9));
+        // the list shrank since
+        assert_eq!(step_index(12, 1, 10), Some(3));
+        assert_eq!(step_index(0, 1, 0), None);
+    }
+}
@@ -0,0 +1,255 @@
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, info};
+
+use crate::client::known_hosts::{KnownHosts, TofuVerifier};
+use crate::client::quic_service::QuicService;
+use crate::client::tcp_service::TcpService;
+use crate::client::unix_service::UnixService;
+use crate::model::{
+    read_frame, write_frame, Capabilities, Hello, HelloReply, Wire, PROTOCOL_VERSION,
+};
+use crate::tls::{ca_verifier, client_config, Identity};
+use crate::transport::{
+    address, Acceptor, ClientTransport, Connection, Datagrams, Reader, Stream, TransportKind, Writer,
+};
+
+/// Wait before retrying a failed reconnect, doubled after each failure
+const FIRST_RETRY: Duration = Duration::from_millis(500);
+/// Longest wait between reconnect attempts
+const MAX_RETRY: Duration = Duration::from_secs(30);
+
+/// How the client decides to trust the server's certificate
+pub enum Trust {
+    /// The chain must lead to this PEM certificate
+    Ca(String),
+    /// Pin whatever the server presents first, then insist on it
+    FirstUse(KnownHosts),
+}
+
+/// Known hosts store and the fingerprint the server presented
+struct Pinning {
+    known_hosts: KnownHosts,
+    presented: Arc<Mutex<Option<String>>>,
+}
+
+/// A connection the server accepted
+pub struct Connected {
+    /// Halves of the request stream
+    pub receive: Reader,
+    pub send: Writer,
+    /// Streams the server opens, carrying events and fetched files.
+    /// The event stream only shows up with the first event.
+    pub streams: Box<dyn Acceptor>,
+    /// Where arrow keys go, if the server agreed to datagrams
+    pub datagrams: Option<Arc<dyn Datagrams>>,
+    /// How requests are encoded and compressed
+    pub wire: Wire,
+}
+
+/// Connects to a server over any transport and makes the handshake
+pub struct Service {
+    transport: Box<dyn ClientTransport>,
+    host: String,
+    pinning: Option<Pinning>,
+}
+
+impl Service {
+    /// Reach `host` over `transport`. `trust` decides which server
+    /// certificates are accepted, `server_name` is sent to the server
+    /// and checked against a CA issued certificate, by default the
+    /// name in `host` or else `localhost`. `identity` is the
+    /// client certificate, if any. A Unix socket has no TLS, its `host`
+    /// is the socket's path.
+    pub fn new(
+        transport: TransportKind,
+        host: String,
+        trust: Trust,
+        server_name: Option<&str>,
+        identity: Option<&Identity>,
+    ) -> Result<Service> {
+        if transport == TransportKind::Unix {
+            let transport = Box::new(UnixService::new(&host));
+            return Ok(Service { transport, host, pinning: None });
+        }
+        let (verifier, pinning) = match trust {
+            Trust::Ca(ca) => (ca_verifier(&ca)?, None),
+            Trust::FirstUse(known_hosts) => {
+                let presented = Arc::new(Mutex::new(None));
+                let pinned = known_hosts.get(&host).map(|f| f.to_string());
+                let verifier = Arc::new(TofuVerifier::new(pinned, presented.clone()));
+                (verifier as _, Some(Pinning { known_hosts, presented }))
+            }
+        };
+        let server_name = server_name
+            .or_else(|| address::host_name(&host))
+            .unwrap_or("localhost")
+            .to_string();
+        let config = client_config(verifier, identity)?;
+        let transport: Box<dyn ClientTransport> = match transport {
+            TransportKind::Tcp => Box::new(TcpService::new(&host, &server_name, config)?),
+            _ => Box::new(QuicService::new(&host, &server_name, config)?),
+        };
+        Ok(Service { transport, host, pinning })
+    }
+    /// Connect and handshake
+    pub async fn connect(&mut self) -> Result<Connected> {
+        // bincode is the most compact, the other encodings are for
+        // clients that can't speak it
+        let offered = Capabilities::SUPPORTED.without(Capabilities::ENCODINGS);
+        let (connection, stream, capabilities, wire) = self.open(offered).await?;
+        let (receive, send) = stream.split();
+        let datagrams = connection
+            .datagrams
+            .filter(|_| capabilities.contains(Capabilities::DATAGRAMS));
+        Ok(Connected {
+            receive,
+            send,
+            streams: connection.acceptor,
+            datagrams,
+            wire,
+        })
+    }
+    /// Connect again after the connection was lost, waiting longer
+    /// after each failed attempt. `retrying` is told why an attempt
+    /// failed and how long until the next. Gives up only when the
+    /// server won't have us or its certificate can't be trusted, as
+    /// retrying can't fix that.
+    pub async fn reconnect<F>(&mut self, mut retrying: F) -> Result<Connected>
+    where
+        F: FnMut(&anyhow::Error, Duration),
+    {
+        let mut delay = FIRST_RETRY;
+        loop {
+            match self.connect().await {
+                Err(e) if is_transient(&e) => {
+                    retrying(&e, delay);
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY);
+                }
+                result => return result,
+            }
+        }
+    }
+    /// Connect asking to pair. Returns the stream the pairing exchange
+    /// runs on, and how it is packed.
+    pub async fn pair(mut self) -> Result<(Stream, Wire)> {
+        let (_, stream, capabilities, wire) = self.open(Capabilities::PAIRING).await?;
+        if !capabilities.contains(Capabilities::PAIRING) {
+            bail!("server did not agree to pair");
+        }
+        Ok((stream, wire))
+    }
+    /// Connect and open the first stream with a Hello asking for
+    /// `capabilities`. Returns the connection, the stream, the
+    /// capabilities the server agreed to and how messages are packed.
+    async fn open(&mut self, capabilities: Capabilities) -> Result<(Connection, Stream, Capabilities, Wire)> {
+        let attempt = self.transport.connect().await;
+        if let Some(pinning) = &self.pinning {
+            pinning.check(&self.host)?;
+        }
+        let connection = attempt?;
+
+        let mut stream = connection.opener.open_bidirectional().await?;
+        let (version, capabilities) = handshake(&mut stream, capabilities).await?;
+        debug!("negotiated capabilities {:?}", capabilities);
+        // only a server that speaks viewd gets pinned
+        if let Some(pinning) = &mut self.pinning {
+            pinning.pin_if_new(&self.host)?;
+        }
+        Ok((connection, stream, capabilities, Wire::negotiated(version, capabilities)))
+    }
+}
+impl Pinning {
+    /// Explain a certificate that doesn't match the pinned one.
+    fn check(&self, host: &str) -> Result<()> {
+        let presented = self.presented.lock().unwrap().clone();
+        match (self.known_hosts.get(host), presented) {
+            (Some(pinned), Some(presented)) if pinned != presented => Err(Refused(format!(
+                "certificate of {host} has changed!\n\
+                 pinned:    {pinned}\n\
+                 presented: {presented}\n\
+                 if the server was given a new certificate, run `viewd client forget {host}`"
+            ))
+            .into()),
+            _ => Ok(()),
+        }
+    }
+    fn pin_if_new(&mut self, host: &str) -> Result<()> {
+        let presented = self.presented.lock().unwrap().clone();
+        if let (None, Some(presented)) = (self.known_hosts.get(host), presented) {
+            self.known_hosts.pin(host, &presented)?;
+            info!("pinned certificate of {} ({})", host, presented);
+        }
+        Ok(())
+    }
+}
+
+/// The server was reached but won't have this client, or isn't the
+/// server it claims to be. Trying again can't change that.
+#[derive(Debug)]
+struct Refused(String);
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+/// Quic transport error codes carrying a TLS alert
+const TLS_ALERTS: RangeInclusive<u64> = 0x100..=0x1ff;
+
+/// Whether `e` may go away by trying again. Anything short of the server
+/// refusing the client or TLS failing to verify a certificate might.
+fn is_transient(e: &anyhow::Error) -> bool {
+    if e.downcast_ref::<Refused>().is_some() {
+        return false;
+    }
+    if let Some(s2n_quic::connection::Error::Transport { code, .. }) = e.downcast_ref() {
+        return !TLS_ALERTS.contains(&code.as_u64());
+    }
+    let tls = e
+        .downcast_ref::<std::io::Error>()
+        .and_then(|e| e.get_ref())
+        .is_some_and(|inner| inner.is::<rustls::Error>());
+    !tls
+}
+
+/// Send our Hello and wait for the server to accept it. Returns the
+/// agreed version and capabilities.
+async fn handshake(stream: &mut Stream, capabilities: Capabilities) -> Result<(u16, Capabilities)> {
+    write_frame(stream, &Hello::new(capabilities).to_bytes()?).await?;
+    let bytes = read_frame(stream)
+        .await?
+        .ok_or(anyhow!("server closed the stream during handshake"))?;
+    match HelloReply::from_bytes(bytes)? {
+        HelloReply::Accept { version, capabilities } => {
+            debug!("server accepted protocol version {}", version);
+            Ok((version, capabilities))
+        }
+        HelloReply::Reject { version, reason } => {
+            debug!("server protocol version {}, ours {}", version, PROTOCOL_VERSION);
+            Err(Refused(format!("server refused this client: {}", reason)).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(&anyhow!("server closed the stream during handshake")));
+        assert!(is_transient(&std::io::Error::from(std::io::ErrorKind::ConnectionReset).into()));
+        assert!(!is_transient(&Refused("server refused this client".to_string()).into()));
+        let untrusted = rustls::Error::InvalidCertificate(rustls::CertificateError::UnknownIssuer);
+        let tls = std::io::Error::new(std::io::ErrorKind::InvalidData, untrusted);
+        assert!(!is_transient(&tls.into()));
+    }
+}
@@ -0,0 +1,41 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use crate::transport::mux::{self, Side};
+use crate::transport::{address, ClientTransport, Connection};
+
+/// Client side of TLS over TCP, for networks that block UDP
+pub struct TcpService {
+    connector: TlsConnector,
+    host: String,
+    server_name: ServerName<'static>,
+}
+
+impl TcpService {
+    /// Reach `host` with TLS set up by `config`, sending `server_name`.
+    pub fn new(host: &str, server_name: &str, config: ClientConfig) -> Result<TcpService> {
+        Ok(TcpService {
+            connector: TlsConnector::from(Arc::new(config)),
+            host: host.to_string(),
+            server_name: ServerName::try_from(server_name.to_string())?,
+        })
+    }
+}
+
+#[async_trait]
+impl ClientTransport for TcpService {
+    async fn connect(&mut self) -> Result<Connection> {
+        // tries each address in turn
+        let addresses = address::resolve(&self.host).await?;
+        let stream = TcpStream::connect(&addresses[..]).await?;
+        stream.set_nodelay(true)?;
+        let stream = self.connector.connect(self.server_name.clone(), stream).await?;
+        Ok(mux::connection(stream, Side::Dialer))
+    }
+}
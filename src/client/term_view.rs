@@ -1,21 +1,42 @@
 use console::Term;
-use anyhow::Result;
-use s2n_quic::stream::ReceiveStream;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
 use tokio::io::{AsyncWriteExt, Stdout};
+use tokio::sync::mpsc::{self, UnboundedSender};
 use std::os::unix::prelude::OsStrExt;
-use std::sync::{mpsc::Sender, Arc, Mutex};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::io::{Stdin, Read};
 use raw_tty::{IntoRawMode, RawReader};
 use terminal_keycode::{Decoder, KeyCode};
 use tokio::task::JoinHandle;
+use tracing::debug;
 
+use crate::client::download;
 use crate::image::Image;
-use crate::model::Response;
+use crate::model::{read_frame, Position, Response, ServerEvent, Status, StreamHeader, Wire};
+use crate::transport::{Acceptor, Reader};
+
+/// Anything the server sends that ends up on screen
+enum Update {
+    Response(Response),
+    Event(ServerEvent),
+    // Percentage of a fetched file written to disk
+    Progress(String, u64),
+    // A fetched file is complete, or only the part asked for
+    Saved(String, bool),
+    // A fetched file could not be saved, and why
+    Failed(String, String),
+    // What the client itself has to say, about the connection
+    Status(String),
+}
 
 /// Handles terminal output
 pub struct TermView {
     term: Term,
     stdout: Stdout,
+    on_display: Arc<Mutex<Option<String>>>,
+    position: Arc<Mutex<Option<Position>>>,
 }
 impl TermView {
     pub fn new() -> Result<TermView> {
@@ -24,9 +45,28 @@ impl TermView {
 	term.clear_screen()?;
 	term.write_line("Viewd!")?;
 	term.write_line("\r--------")?;
-	let view = TermView { term, stdout };
+	let on_display = Arc::new(Mutex::new(None));
+	let position = Arc::new(Mutex::new(None));
+	let view = TermView { term, stdout, on_display, position };
 	Ok(view)
     }
+    /// Name of the image on display, as far as the server has told
+    pub fn on_display(&self) -> Arc<Mutex<Option<String>>> {
+	self.on_display.clone()
+    }
+    /// Where the image on display is in the server's list, as far as
+    /// the server has told
+    pub fn position(&self) -> Arc<Mutex<Option<Position>>> {
+	self.position.clone()
+    }
+    /// Remember the image at `path` as the one on display, at `position`
+    fn showing(&self, path: &Path, position: Option<Position>) {
+	let name = path.file_name().map(|n| n.to_string_lossy().into_owned());
+	*self.on_display.lock().unwrap() = name;
+	if position.is_some() {
+	    *self.position.lock().unwrap() = position;
+	}
+    }
     /// Accepts some text bytes and handles writing to stdout
     /// with some formatting.
     pub async fn write_line(&mut self, line: &[u8]) -> Result<()> {
@@ -36,25 +76,133 @@ impl TermView {
 	self.stdout.flush().await?;
 	Ok(())
     }
-    /// Spawn a task to handle writes
-    pub fn stdout_task(mut self, mut stream: ReceiveStream) -> JoinHandle<Result<()>> {
-	tokio::spawn(async move {
-	    while let Ok(Some(data)) = stream.receive().await {
-		let response = Response::from_bytes(data)?;
-		let path = if let Some(path) = response.path() {
-		   path 
-		} else {
-		    unimplemented!()
-		};
-		if let Some(image) = Image::new(path) {
-		    self.write_line(image.name().as_bytes()).await?;
-		};
+    /// Spawn a task to handle writes to stdout. What to write comes
+    /// through the returned `Updates`, from each connection attached
+    /// to it.
+    pub fn stdout_task(mut self) -> (JoinHandle<Result<()>>, Updates) {
+	let (tx, mut rx) = mpsc::unbounded_channel::<Update>();
+	let handle = tokio::spawn(async move {
+	    while let Some(update) = rx.recv().await {
+		match update {
+		    Update::Response(response) if response.status() != Status::Success => {
+			self.write_line(response.message().as_bytes()).await?;
+		    }
+		    Update::Response(response) => {
+			debug!("response to request {}", response.id());
+			// nothing on display, say what the server did instead
+			let Some(path) = response.path() else {
+			    self.write_line(response.message().as_bytes()).await?;
+			    continue;
+			};
+			self.showing(path, response.position());
+			if let Some(image) = Image::new(path) {
+			    self.write_line(image.name().as_bytes()).await?;
+			};
+		    }
+		    Update::Event(ServerEvent::ImageChanged(path, position)) => {
+			self.showing(&path, position);
+			if let Some(image) = Image::new(&path) {
+			    self.write_line(image.name().as_bytes()).await?;
+			};
+		    }
+		    Update::Event(event) => {
+			self.write_line(event.to_string().as_bytes()).await?;
+		    }
+		    Update::Progress(name, percent) => {
+			self.write_line(format!("{} {}%", name, percent).as_bytes()).await?;
+		    }
+		    Update::Saved(name, true) => {
+			self.write_line(format!("saved {}", name).as_bytes()).await?;
+		    }
+		    Update::Saved(name, false) => {
+			self.write_line(format!("saved part of {}", name).as_bytes()).await?;
+		    }
+		    Update::Failed(name, reason) => {
+			self.write_line(format!("fetching {} failed: {}", name, reason).as_bytes()).await?;
+		    }
+		    Update::Status(line) => {
+			self.write_line(line.as_bytes()).await?;
+		    }
+		}
 	    }
 	    Ok(())
-	})
+	});
+	(handle, Updates { tx })
     }
 }
 
+/// Feeds the terminal view, outliving any one connection
+#[derive(Clone)]
+pub struct Updates {
+    tx: UnboundedSender<Update>,
+}
+impl Updates {
+    /// Show responses, and the events and downloads arriving on streams
+    /// the server opens, all packed as `wire` says. The returned task
+    /// ends when the connection is lost.
+    pub fn attach(&self, responses: Reader, mut streams: Box<dyn Acceptor>, wire: Wire) -> JoinHandle<Result<()>> {
+	let tx = self.tx.clone();
+	tokio::spawn(async move {
+	    while let Ok(Some(stream)) = streams.accept_receive().await {
+		let tx = tx.clone();
+		tokio::spawn(async move {
+		    if let Err(e) = accept(stream, tx, wire).await {
+			debug!("server stream failed: {}", e);
+		    }
+		});
+	    }
+	});
+	forward(responses, self.tx.clone(), move |b| Response::from_bytes(b, wire).map(Update::Response))
+    }
+    /// Show a line from the client itself
+    pub fn status(&self, line: &str) {
+	self.tx.send(Update::Status(line.to_string())).ok();
+    }
+}
+
+/// Decode frames from `stream` and pass them to the view until the
+/// stream ends.
+fn forward<F>(mut stream: Reader, tx: UnboundedSender<Update>, decode: F) -> JoinHandle<Result<()>>
+where
+    F: Fn(Bytes) -> Result<Update> + Send + 'static,
+{
+    tokio::spawn(async move {
+	while let Some(data) = read_frame(&mut stream).await? {
+	    if tx.send(decode(data)?).is_err() {
+		break;
+	    }
+	}
+	Ok(())
+    })
+}
+
+/// Read the header of a stream the server opened and handle the rest
+/// of it accordingly.
+async fn accept(mut stream: Reader, tx: UnboundedSender<Update>, wire: Wire) -> Result<()> {
+    let header = read_frame(&mut stream)
+	.await?
+	.ok_or(anyhow!("stream closed before its header"))?;
+    let (info, compressed) = match StreamHeader::from_bytes(header, wire)? {
+	StreamHeader::Events => {
+	    forward(stream, tx, move |b| ServerEvent::from_bytes(b, wire).map(Update::Event));
+	    return Ok(());
+	}
+	StreamHeader::Download(info) => (info, false),
+	StreamHeader::ZstdDownload(info) => (info, true),
+    };
+    debug!("download for request {}", info.id);
+    let name = info.name.clone();
+    let progress = |percent| {
+	tx.send(Update::Progress(name.clone(), percent)).ok();
+    };
+    let update = match download::download(Path::new("."), stream, info, compressed, progress).await {
+	Ok(complete) => Update::Saved(name, complete),
+	Err(e) => Update::Failed(name, e.to_string()),
+    };
+    tx.send(update).ok();
+    Ok(())
+}
+
 /// Encapsulates terminal input
 pub struct TermInput {
     stdin: RawReader<Stdin>,
@@ -72,7 +220,7 @@ impl TermInput {
     pub fn stdin_task(
 	mut self,
 	should_exit: Arc<Mutex<bool>>,
-	tx: Sender<KeyCode>,
+	tx: UnboundedSender<KeyCode>,
     ) -> JoinHandle<Result<()>> {
 	tokio::spawn(async move {
             loop {
@@ -0,0 +1,27 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::net::UnixStream;
+
+use crate::transport::mux::{self, Side};
+use crate::transport::{ClientTransport, Connection};
+
+/// Client side of a Unix socket, for a server on the same machine
+pub struct UnixService {
+    path: PathBuf,
+}
+
+impl UnixService {
+    pub fn new(path: &str) -> UnixService {
+        UnixService { path: PathBuf::from(path) }
+    }
+}
+
+#[async_trait]
+impl ClientTransport for UnixService {
+    async fn connect(&mut self) -> Result<Connection> {
+        let stream = UnixStream::connect(&self.path).await?;
+        Ok(mux::connection(stream, Side::Dialer))
+    }
+}
@@ -0,0 +1,206 @@
+//! Finding servers on the local network. A client broadcasts a query
+//! over UDP and every server listening for it answers with an
+//! `Announcement` of where and how to reach it.
+
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::time::{timeout_at, Instant};
+use tracing::{debug, info, warn};
+
+use crate::model::PROTOCOL_VERSION;
+use crate::transport::TransportKind;
+
+/// UDP port servers listen for queries on
+pub const DISCOVERY_PORT: u16 = 4434;
+
+/// Where clients send their query unless told otherwise
+pub const BROADCAST: &str = "255.255.255.255";
+
+/// Time clients wait for answers
+pub const DISCOVERY_WAIT: Duration = Duration::from_secs(1);
+
+/// Leading bytes of a query
+const QUERY: [u8; 4] = *b"VWD?";
+
+/// Size of a query, padded with zeros after `QUERY`. No bigger than an
+/// answer may be, so that a query sent from a forged address can't be
+/// turned into more traffic towards it.
+const QUERY_SIZE: usize = 512;
+
+/// Leading bytes of every `Announcement`
+const ANNOUNCEMENT_MAGIC: [u8; 4] = *b"VWD!";
+
+/// A server's answer to a query
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Announcement {
+    magic: [u8; 4],
+    /// Protocol version the server speaks
+    pub version: u16,
+    /// Name to show in the list, by default the server's host name
+    pub name: String,
+    /// Address the server is bound to. None when it listens on all
+    /// of them, the answer then came from the one to use.
+    pub ip: Option<IpAddr>,
+    pub port: u16,
+    /// `quic` or `tcp`
+    pub transport: String,
+    /// Fingerprint of the server certificate, to compare with the one
+    /// pinned on first connect
+    pub fingerprint: String,
+}
+
+impl Announcement {
+    pub fn new(name: &str, bind: SocketAddr, transport: TransportKind, fingerprint: &str) -> Self {
+        let ip = Some(bind.ip()).filter(|ip| !ip.is_unspecified());
+        Announcement {
+            magic: ANNOUNCEMENT_MAGIC,
+            version: PROTOCOL_VERSION,
+            name: name.to_string(),
+            ip,
+            port: bind.port(),
+            transport: transport.to_string(),
+            fingerprint: fingerprint.to_string(),
+        }
+    }
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        bincode::serialize(&self).map_err(|e| anyhow!("Serialization Error: {}", e))
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Announcement> {
+        let announcement: Announcement =
+            bincode::deserialize(bytes).map_err(|e| anyhow!("Deserialization Error: {}", e))?;
+        if announcement.magic != ANNOUNCEMENT_MAGIC {
+            return Err(anyhow!("Deserialization Error: not a viewd announcement"));
+        }
+        Ok(announcement)
+    }
+}
+
+/// A server that answered, and the address to connect to
+#[derive(Debug, Clone)]
+pub struct Found {
+    pub address: String,
+    pub announcement: Announcement,
+}
+
+/// Server side, answering queries on the discovery port
+pub struct Announcer {
+    socket: UdpSocket,
+    reply: Vec<u8>,
+}
+
+impl Announcer {
+    /// Listen for queries on `port`, several servers on one machine
+    /// sharing it, and answer with `announcement`.
+    pub fn new(port: u16, announcement: &Announcement) -> Result<Announcer> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        // every socket bound to the port gets a copy of each broadcast
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port).into())?;
+        let socket = UdpSocket::from_std(socket.into())?;
+        info!("answering discovery on port {} as {}", port, announcement.name);
+        let reply = announcement.to_bytes()?;
+        if reply.len() > QUERY_SIZE {
+            return Err(anyhow!("Discovery Error: the announcement is longer than a query, shorten the name"));
+        }
+        Ok(Announcer { socket, reply })
+    }
+
+    pub fn listen_task(self) {
+        tokio::spawn(async move {
+            let mut query = [0u8; QUERY_SIZE + 1];
+            loop {
+                let (n, from) = match self.socket.recv_from(&mut query).await {
+                    Ok(received) => received,
+                    Err(e) => {
+                        warn!("discovery receive failed: {}", e);
+                        continue;
+                    }
+                };
+                if n != QUERY_SIZE || !query.starts_with(&QUERY) {
+                    debug!("ignored {} bytes on the discovery port from {}", n, from);
+                    continue;
+                }
+                if let Err(e) = self.socket.send_to(&self.reply, from).await {
+                    debug!("discovery answer to {} failed: {}", from, e);
+                }
+            }
+        });
+    }
+}
+
+/// Broadcast a query to `target` and gather the servers answering
+/// within `wait`, each once.
+pub async fn discover(target: SocketAddr, wait: Duration) -> Result<Vec<Found>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    let mut query = [0u8; QUERY_SIZE];
+    query[..QUERY.len()].copy_from_slice(&QUERY);
+    socket
+        .send_to(&query, target)
+        .await
+        .map_err(|e| anyhow!("Discovery Error: broadcast to {}: {}", target, e))?;
+    let deadline = Instant::now() + wait;
+    let mut found = Vec::new();
+    let mut seen = HashSet::new();
+    let mut buf = [0u8; 2048];
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (n, from) = received?;
+        let announcement = match Announcement::from_bytes(&buf[..n]) {
+            Ok(announcement) => announcement,
+            Err(e) => {
+                debug!("bad announcement from {}: {}", from, e);
+                continue;
+            }
+        };
+        let ip = announcement.ip.unwrap_or(from.ip());
+        let address = SocketAddr::new(ip, announcement.port).to_string();
+        if seen.insert(address.clone()) {
+            found.push(Found { address, announcement });
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_discover_two_servers() -> Result<()> {
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")?.local_addr()?.port();
+        let frame = Announcement::new("frame", "0.0.0.0:4433".parse()?, TransportKind::Quic, "4f:1c");
+        let wall = Announcement::new("wall", "127.0.0.1:4500".parse()?, TransportKind::Tcp, "a0:3d");
+        Announcer::new(port, &frame)?.listen_task();
+        Announcer::new(port, &wall)?.listen_task();
+
+        let target = SocketAddr::new(Ipv4Addr::new(127, 255, 255, 255).into(), port);
+        let mut found = discover(target, Duration::from_millis(500)).await?;
+        found.sort_by(|a, b| a.announcement.name.cmp(&b.announcement.name));
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].announcement, frame);
+        assert_eq!(found[0].address, "127.0.0.1:4433");
+        assert_eq!(found[1].announcement, wall);
+        assert_eq!(found[1].announcement.transport.parse::<TransportKind>()?, TransportKind::Tcp);
+        assert_eq!(found[1].address, "127.0.0.1:4500");
+
+        assert!(Announcement::from_bytes(&QUERY).is_err());
+
+        // a bare query gets nothing back
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        socket.send_to(&QUERY, (Ipv4Addr::LOCALHOST, port)).await?;
+        let mut buf = [0u8; 2048];
+        let answer = tokio::time::timeout(Duration::from_millis(200), socket.recv_from(&mut buf)).await;
+        assert!(answer.is_err());
+
+        let long = Announcement::new(&"x".repeat(QUERY_SIZE), "0.0.0.0:4433".parse()?, TransportKind::Quic, "4f:1c");
+        assert!(Announcer::new(port, &long).is_err());
+        Ok(())
+    }
+}
@@ -1,39 +1,40 @@
 use std::path::Path;
-use std::{path::PathBuf, ffi::OsString};
-use std::fs;
-use anyhow::{anyhow, Result};
-use tracing::debug;
+use std::ffi::OsString;
 
 // TODO use this!
 
 /// Representation of an image
 pub struct Image {
-    path: PathBuf,
     name: OsString,
-    file_name: OsString
 }
 
 impl Image {
     pub fn new(path: &Path) -> Option<Image> {
 	let name = path.file_stem()?.to_owned();
-	let file_name = path.file_name()?.to_owned();
-	let path = path.to_path_buf();
-	let i = Image { path, name, file_name };
+	let i = Image { name };
 	Some(i)
     } 
-    pub fn path(&self) -> PathBuf {
-        let path = &self.path;
-	path.to_path_buf()
-    }
     pub fn name(&self) -> OsString {
         self.name.clone()
     }
-    pub fn file_name(&self) -> OsString {
-	self.file_name.clone()
-    }
-    pub fn data(&self) -> Result<Vec<u8>> {
-        let path = &self.path;
-	debug!("opening file at {:?}", path);
-	fs::read(path).map_err(|e|anyhow!("Image Data Error: {}", e))
+}
+
+/// Media type of an image, going by its extension
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().map(|e| e.to_string_lossy().to_ascii_lowercase());
+    match extension.as_deref() {
+	Some("jpg" | "jpeg") => "image/jpeg",
+	Some("png") => "image/png",
+	Some("gif") => "image/gif",
+	Some("bmp") => "image/bmp",
+	Some("webp") => "image/webp",
+	Some("tif" | "tiff") => "image/tiff",
+	_ => "application/octet-stream",
     }
 }
+
+/// Whether images of `content_type` are compressed already, so that
+/// compressing them again would gain nothing
+pub fn is_compressed(content_type: &str) -> bool {
+    matches!(content_type, "image/jpeg" | "image/png" | "image/gif" | "image/webp")
+}
@@ -3,15 +3,25 @@
 use std::path::PathBuf;
 use anyhow::Result;
 use clap::{Parser, Subcommand, Args};
-use tracing::{error, Level, debug};
-use client::run_client;
+use tracing::{error, info, Level, debug};
+use client::{cast_file, discover_server, forget_host, list_hosts, pair_client, run_client, upload_file};
 
-use crate::server::Server;
+use crate::discovery::BROADCAST;
+use crate::model::Role;
+use crate::server::{
+    Admission, Allowlist, Endpoints, Limits, Server, DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_STREAMS,
+    DEFAULT_MAX_UPLOAD_MIB, DEFAULT_RATE,
+};
+use crate::tls::Identity;
+use crate::transport::TransportKind;
 
 mod client;
 mod server;
 mod model;
 mod image;
+mod tls;
+mod transport;
+mod discovery;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -29,16 +39,164 @@ enum Command {
 
 #[derive(Args, Debug, Clone)]
 struct ServerArgs {
-    #[arg(default_value = "127.0.0.1:4433")]
-    bind: String,
+    /// Addresses to listen on, or the socket path with `--transport
+    /// unix`. `[::]:4433` takes both IPv4 and IPv6 clients, a host name
+    /// is listened on at each of its addresses.
+    #[arg(default_value = "127.0.0.1:4433", num_args = 1..)]
+    bind: Vec<String>,
+    /// `quic`, `tcp` (TLS over TCP) or `unix` (a local socket)
+    #[arg(long, default_value = "quic")]
+    transport: TransportKind,
     #[arg(short, long)]
-    path: PathBuf
+    path: PathBuf,
+    /// PEM certificate to serve. Defaults to a self-signed certificate
+    /// generated in the config directory on first run.
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+    /// PEM private key for `--cert`
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+    /// Only serve clients presenting a certificate whose fingerprint
+    /// is listed in this file, one per line. Paired clients are added.
+    #[arg(long)]
+    allowlist: Option<PathBuf>,
+    /// Role given to clients that pair, `viewer` or `controller`. A
+    /// client asking for less gets less.
+    #[arg(long, default_value = "viewer", requires = "allowlist")]
+    pair_role: Role,
+    /// Largest image clients may upload, in MiB
+    #[arg(long, default_value_t = DEFAULT_MAX_UPLOAD_MIB)]
+    max_upload: u64,
+    /// Most client connections open at once
+    #[arg(long, default_value_t = DEFAULT_MAX_CONNECTIONS)]
+    max_connections: usize,
+    /// Most request streams a connection may have open at once
+    #[arg(long, default_value_t = DEFAULT_MAX_STREAMS)]
+    max_streams: usize,
+    /// Commands changing the display each client may send per second,
+    /// with bursts of twice as many. Status and fetches may come four
+    /// times as often, uploads and casts half. 0 turns the limit off.
+    #[arg(long, default_value_t = DEFAULT_RATE)]
+    rate_limit: f64,
+    /// Also take text commands (`next`, `prev`, `goto N`, `status`)
+    /// from scripts on this machine on a Unix socket at this path
+    #[arg(long)]
+    control: Option<PathBuf>,
+    /// Also serve a JSON API and a remote control page for browsers
+    /// over plain HTTP on this address, such as 127.0.0.1:8080. Only
+    /// loopback addresses unless `--http-tokens` is given.
+    #[arg(long)]
+    http: Option<String>,
+    /// Only serve HTTP callers presenting one of the bearer tokens in
+    /// this file, one `token [role]` per line
+    #[arg(long, requires = "http")]
+    http_tokens: Option<PathBuf>,
+    /// Also serve Prometheus metrics at /metrics over plain HTTP on
+    /// this address, such as 0.0.0.0:9464
+    #[arg(long)]
+    metrics: Option<String>,
+    /// Name clients looking for servers on the network see, by default
+    /// the host name
+    #[arg(long, requires = "discovery")]
+    name: Option<String>,
+    /// Answer clients looking for servers on the network, on UDP port
+    /// 4434 of every interface
+    #[arg(long)]
+    discovery: bool,
 }
 
 #[derive(Args, Debug, Clone)]
+#[command(args_conflicts_with_subcommands = true)]
 struct ClientArgs {
+    #[command(subcommand)]
+    action: Option<ClientAction>,
+    /// Look for servers on the local network and connect to one
+    #[arg(long, conflicts_with_all = ["host", "transport"])]
+    discover: bool,
+    /// Address to send the discovery broadcast to, 127.255.255.255 to
+    /// only look on this machine
+    #[arg(long, default_value = BROADCAST, requires = "discover")]
+    broadcast: String,
+    #[command(flatten)]
+    connect: ConnectArgs,
+}
+
+/// How a client reaches and authenticates to a server
+#[derive(Args, Debug, Clone)]
+struct ConnectArgs {
+    /// Server address, such as frame.local:4433 or [fe80::1]:4433, the
+    /// port defaulting to 4433. Or the socket path with `--transport unix`.
     #[arg(default_value = "127.0.0.1:4433")]
     host: String,
+    /// `quic`, `tcp` (TLS over TCP) or `unix` (a local socket)
+    #[arg(long, default_value = "quic")]
+    transport: TransportKind,
+    /// PEM certificate the server must present or be signed by.
+    /// Without it the server certificate is pinned on first connect.
+    #[arg(long)]
+    ca: Option<PathBuf>,
+    /// Name the server certificate must be issued for. Defaults to the
+    /// host name in the address, or localhost for an IP address.
+    #[arg(long)]
+    server_name: Option<String>,
+    /// PEM certificate to authenticate this client with
+    #[arg(long, requires = "client_key")]
+    client_cert: Option<PathBuf>,
+    /// PEM private key for `--client-cert`
+    #[arg(long, requires = "client_cert")]
+    client_key: Option<PathBuf>,
+}
+
+impl ConnectArgs {
+    /// The client certificate given on the command line, if any
+    fn identity(&self) -> Result<Option<Identity>> {
+	match (&self.client_cert, &self.client_key) {
+	    (Some(cert), Some(key)) => Ok(Some(Identity::load(cert, key)?)),
+	    _ => Ok(None),
+	}
+    }
+}
+
+#[derive(Clone, Debug, Subcommand)]
+enum ClientAction {
+    /// List pinned servers and their certificate fingerprints
+    Hosts,
+    /// Forget the certificate pinned for a server
+    Forget { host: String },
+    /// Pair with a server using the PIN it shows on its display
+    Pair {
+	#[arg(default_value = "127.0.0.1:4433")]
+	host: String,
+	/// `quic` or `tcp` (TLS over TCP)
+	#[arg(long, default_value = "quic")]
+	transport: TransportKind,
+	/// Name to be listed under on the server, defaults to the host name
+	#[arg(long)]
+	name: Option<String>,
+	/// Role to ask for. `viewer` only watches and fetches, `controller`
+	/// also changes what is on display. The server may grant less.
+	#[arg(long, default_value = "controller")]
+	role: Role,
+	/// Name the server certificate was issued for, by default the
+	/// host name in the address or localhost
+	#[arg(long)]
+	server_name: Option<String>,
+    },
+    /// Add an image to the server's image directory
+    Upload {
+	file: PathBuf,
+	/// Show the image once it is added
+	#[arg(long)]
+	show: bool,
+	#[command(flatten)]
+	connect: ConnectArgs,
+    },
+    /// Show an image on the display for a while, without storing it
+    Cast {
+	file: PathBuf,
+	#[command(flatten)]
+	connect: ConnectArgs,
+    },
 }
 
 #[tokio::main]
@@ -50,18 +208,59 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Command::Server(ServerArgs { bind, path }) => {
-	    debug! {"bind to host: {}", bind};
+        Command::Server(ServerArgs { bind, transport, path, cert, key, allowlist, pair_role, max_upload, max_connections, max_streams, rate_limit, control, http, http_tokens, metrics, name, discovery }) => {
+	    debug! {"bind to hosts: {}", bind.join(", ")};
 	    debug! {"images path: {}", &path.as_path().display()};
 
-	    let server = Server::new(bind, &path)?;
+	    let identity = match (cert, key) {
+		(Some(cert), Some(key)) => Identity::load(&cert, &key)?,
+		_ => Identity::load_or_generate(&tls::config_dir()?)?,
+	    };
+	    info!("certificate fingerprint {}", identity.fingerprint()?);
+	    let admission = allowlist
+		.map(|path| Allowlist::load(&path).map(|allowlist| Admission { allowlist, pair_role }))
+		.transpose()?;
+	    let discovery = match discovery {
+		false => None,
+		true => Some(name
+		    .or_else(|| hostname::get().ok().and_then(|h| h.into_string().ok()))
+		    .unwrap_or_else(|| "viewd".to_string())),
+	    };
+	    let endpoints = Endpoints { control, http, http_tokens, metrics, discovery };
+	    let limits = Limits {
+		max_upload: max_upload * 1024 * 1024,
+		max_connections,
+		max_streams,
+		rate: rate_limit,
+	    };
+	    let server = Server::new(bind, transport, &path, &identity, admission, limits, endpoints)?;
 	    if let Err(e) = server.run() {
 	    	error!("failed {reason}", reason = e.to_string());
 	    }
         }
-        Command::Client(ClientArgs { host }) => {
+        Command::Client(ClientArgs { action: Some(ClientAction::Hosts), .. }) => list_hosts()?,
+        Command::Client(ClientArgs { action: Some(ClientAction::Forget { host }), .. }) => forget_host(&host)?,
+        Command::Client(ClientArgs { action: Some(ClientAction::Pair { host, transport, name, role, server_name }), .. }) => {
+	    pair_client(host, transport, server_name.as_deref(), name, role).await?
+	}
+        Command::Client(ClientArgs { action: Some(ClientAction::Upload { file, show, connect }), .. }) => {
+	    let identity = connect.identity()?;
+	    let ConnectArgs { host, transport, ca, server_name, .. } = connect;
+	    upload_file(host, transport, ca.as_deref(), server_name.as_deref(), identity, &file, show).await?
+	}
+        Command::Client(ClientArgs { action: Some(ClientAction::Cast { file, connect }), .. }) => {
+	    let identity = connect.identity()?;
+	    let ConnectArgs { host, transport, ca, server_name, .. } = connect;
+	    cast_file(host, transport, ca.as_deref(), server_name.as_deref(), identity, &file).await?
+	}
+        Command::Client(ClientArgs { action: None, discover, broadcast, connect }) => {
+	    let identity = connect.identity()?;
+	    let ConnectArgs { mut host, mut transport, ca, server_name, .. } = connect;
+	    if discover {
+		(host, transport) = discover_server(&broadcast).await?;
+	    }
 	    debug! {"connect to host: {}", host};
-	    if let Err(e) = run_client(host).await {
+	    if let Err(e) = run_client(host, transport, ca.as_deref(), server_name.as_deref(), identity).await {
 		error!("failed {reason}", reason = e.to_string());
 	    }
 	}
@@ -1,16 +1,256 @@
 use std::path::Path;
 use std::path::PathBuf;
+use std::borrow::Cow;
 use std::fmt;
+use std::io::ErrorKind;
+use std::str::FromStr;
 use bytes::Bytes;
-use serde::{Serialize, Deserialize};
+use serde::{de::DeserializeOwned, Serialize, Deserialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tracing::debug;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 
 use terminal_keycode::KeyCode;
 
+/// ALPN token offered by clients and required by the server. The
+/// protocol version is carried by `Hello` instead, so that a version
+/// mismatch produces a readable error rather than a TLS alert.
+pub const ALPN: &[u8] = b"viewd";
+
+/// Version of the Request/Response wire format spoken by this build.
+pub const PROTOCOL_VERSION: u16 = 11;
+
+/// Oldest protocol version this build still accepts. Only raised when
+/// the layout of a message changes, features that older peers can do
+/// without are offered through `Capabilities` instead.
+pub const MIN_PROTOCOL_VERSION: u16 = 9;
+
+/// Leading bytes of every `Hello`, to tell a viewd handshake apart
+/// from anything else written to the stream.
+const HELLO_MAGIC: [u8; 4] = *b"VIEW";
+
+/// Optional protocol features as a bit set, negotiated during the
+/// handshake. The server answers with the subset both sides support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Server opens a unidirectional stream of `ServerEvent`s, headed
+    /// by `StreamHeader::Events`.
+    pub const EVENTS: Capabilities = Capabilities(1 << 0);
+    /// Client wants to pair rather than send requests. The handshake
+    /// stream then carries `PairRequest`s and `PairReply`s and is the
+    /// only stream of the connection. Only granted by servers that
+    /// keep an allowlist, along with any of `ENCODINGS` asked for, and
+    /// never part of `SUPPORTED`.
+    pub const PAIRING: Capabilities = Capabilities(1 << 1);
+    /// Client sends arrow keys as `Intent` datagrams rather than
+    /// requests. Only granted to controllers on transports that carry
+    /// datagrams.
+    pub const DATAGRAMS: Capabilities = Capabilities(1 << 2);
+    /// Either side may zstd compress what it sends, as `Compression`
+    /// describes.
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 3);
+    /// Both sides send messages as JSON rather than bincode, see
+    /// `Encoding`.
+    pub const JSON: Capabilities = Capabilities(1 << 4);
+    /// Both sides send messages as CBOR. Wins over `JSON` if a client
+    /// asks for both.
+    pub const CBOR: Capabilities = Capabilities(1 << 5);
+    /// Every encoding a client may ask for instead of bincode
+    pub const ENCODINGS: Capabilities = Capabilities(Self::JSON.0 | Self::CBOR.0);
+    /// Features implemented by this build.
+    pub const SUPPORTED: Capabilities =
+	Capabilities(Self::EVENTS.0 | Self::DATAGRAMS.0 | Self::COMPRESSION.0 | Self::ENCODINGS.0);
+
+    pub fn intersection(self, other: Capabilities) -> Capabilities {
+	Capabilities(self.0 & other.0)
+    }
+    pub fn contains(self, other: Capabilities) -> bool {
+	self.0 & other.0 == other.0
+    }
+    pub fn without(self, other: Capabilities) -> Capabilities {
+	Capabilities(self.0 & !other.0)
+    }
+    pub fn with(self, other: Capabilities) -> Capabilities {
+	Capabilities(self.0 | other.0)
+    }
+}
+
+/// First frame a client writes on its control stream. The layout of
+/// `Hello` and `HelloReply` must stay fixed across protocol versions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    magic: [u8; 4],
+    // Protocol version the client speaks.
+    version: u16,
+    // Features the client would like to use.
+    capabilities: Capabilities,
+}
+
+impl Hello {
+    pub fn new(capabilities: Capabilities) -> Hello {
+	Hello {
+	    magic: HELLO_MAGIC,
+	    version: PROTOCOL_VERSION,
+	    capabilities,
+	}
+    }
+    pub fn capabilities(&self) -> Capabilities {
+	self.capabilities
+    }
+    /// Decide whether the server can talk to this client.
+    pub fn reply(&self) -> HelloReply {
+	let reason = if self.magic != HELLO_MAGIC {
+	    "not a viewd handshake".to_string()
+	} else if self.version < MIN_PROTOCOL_VERSION {
+	    format!("client protocol version {} is too old, server requires {} to {}",
+		    self.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)
+	} else if self.version > PROTOCOL_VERSION {
+	    format!("client protocol version {} is too new, server supports {} to {}",
+		    self.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)
+	} else {
+	    return HelloReply::Accept {
+		version: self.version,
+		capabilities: self.capabilities.intersection(Capabilities::SUPPORTED),
+	    };
+	};
+	HelloReply::Reject { version: PROTOCOL_VERSION, reason }
+    }
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+	bincode::serialize(&self)
+            .map_err(|e| anyhow!("Serialization Error: {}", e))
+    }
+    pub fn from_bytes(bytes: Bytes) -> Result<Hello> {
+	bincode::deserialize(&bytes)
+            .map_err(|e| anyhow!("Deserialization Error: {}", e))
+    }
+}
+
+/// Server answer to a `Hello`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HelloReply {
+    // Version both sides will use and the agreed features.
+    Accept { version: u16, capabilities: Capabilities },
+    // The server's own version and why it refused the client.
+    Reject { version: u16, reason: String },
+}
+
+impl HelloReply {
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+	bincode::serialize(&self)
+            .map_err(|e| anyhow!("Serialization Error: {}", e))
+    }
+    pub fn from_bytes(bytes: Bytes) -> Result<HelloReply> {
+	bincode::deserialize(&bytes)
+            .map_err(|e| anyhow!("Deserialization Error: {}", e))
+    }
+}
+
+/// What a client may do. Bound to the client's credential by the
+/// server's allowlist. Ordered from least to most allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Role {
+    // Watch the display and fetch the image on it
+    Viewer,
+    // Everything a viewer can, and change what is on display
+    Controller,
+}
+
+impl Role {
+    pub fn permits(self, command: &ServerCommand) -> bool {
+	match self {
+	    Self::Controller => true,
+	    Self::Viewer => matches!(command, ServerCommand::Fetch { .. } | ServerCommand::Status),
+	}
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+	match s {
+	    "viewer" => Ok(Self::Viewer),
+	    "controller" => Ok(Self::Controller),
+	    _ => bail!("unknown role {}, expected viewer or controller", s),
+	}
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	match self {
+	    Self::Viewer => write!(f, "viewer"),
+	    Self::Controller => write!(f, "controller"),
+	}
+    }
+}
+
+/// PIN read off the display, sent by a pairing client along with the
+/// name it should be listed under and the role it asks for. The server
+/// grants no more than its `--pair-role`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairRequest {
+    pub pin: String,
+    pub name: String,
+    pub role: Role,
+}
+
+impl PairRequest {
+    pub fn to_bytes(&self, wire: Wire) -> Result<Vec<u8>> {
+	wire.pack(self)
+    }
+    pub fn from_bytes(bytes: Bytes, wire: Wire) -> Result<PairRequest> {
+	wire.unpack(&bytes)
+    }
+}
+
+/// Server side of the pairing exchange.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PairReply {
+    // A PIN is on the display, valid for this many seconds
+    PinShown(u64),
+    // Wrong PIN, the client may try again
+    Retry(String),
+    // Pairing is over without a credential
+    Refused(String),
+    // Certificate and key, PEM encoded, the client connects with from
+    // now on, and the role it was given
+    Paired { cert: String, key: String, role: Role },
+}
+
+/// `PairReply` as peers before version 11 know it, without the role
+#[derive(Serialize)]
+enum PairReplyV10<'a> {
+    PinShown(u64),
+    Retry(&'a str),
+    Refused(&'a str),
+    Paired { cert: &'a str, key: &'a str },
+}
+
+impl PairReply {
+    pub fn to_bytes(&self, wire: Wire) -> Result<Vec<u8>> {
+	if wire.version >= 11 {
+	    return wire.pack(self);
+	}
+	wire.pack(&match self {
+	    Self::PinShown(seconds) => PairReplyV10::PinShown(*seconds),
+	    Self::Retry(reason) => PairReplyV10::Retry(reason),
+	    Self::Refused(reason) => PairReplyV10::Refused(reason),
+	    Self::Paired { cert, key, .. } => PairReplyV10::Paired { cert, key },
+	})
+    }
+    pub fn from_bytes(bytes: Bytes, wire: Wire) -> Result<PairReply> {
+	wire.unpack(&bytes)
+    }
+}
+
 // Model for commands sent to server
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Request {
+    // Chosen by the client and echoed back in the matching Response.
+    id: u64,
     // Command to execute on the server.
     command: ServerCommand,
     // Path of image currently on display.
@@ -18,57 +258,451 @@ pub struct Request {
 }
 
 impl Request {
-    pub fn new(code: KeyCode) -> Option<Request> {
-	ServerCommand::from_keycode(code).map(|command| Request { command })
+    pub fn new(id: u64, code: KeyCode) -> Option<Request> {
+	ServerCommand::from_keycode(code).map(|command| Request { id, command })
     }
-    pub fn command(&self) -> ServerCommand {
-	self.command
+    /// Fetch `length` bytes, or the rest, of the image on display
+    /// starting at `offset`.
+    pub fn fetch(id: u64, offset: u64, length: Option<u64>) -> Request {
+	Request { id, command: ServerCommand::Fetch { offset, length } }
     }
-    pub fn to_bytes(&self) -> Result<Vec<u8>> {
-	bincode::serialize(&self)
-            .map_err(|e| anyhow!("Serialization Error: {}", e))
+    /// Store the `size` bytes following this request under `name` in
+    /// the server's image directory, and `show` it right away.
+    pub fn upload(id: u64, name: &str, size: u64, show: bool) -> Request {
+	let name = name.to_string();
+	Request { id, command: ServerCommand::Upload { name, size, show } }
     }
-    pub fn from_bytes(bytes: Bytes) -> Result<Request> {
-	bincode::deserialize(&bytes)
-            .map_err(|e| anyhow!("Deserialization Error: {}", e))
+    /// Show the image of `size` bytes following this request over the
+    /// one on display, until `end_cast`.
+    pub fn cast(id: u64, size: u64) -> Request {
+	Request { id, command: ServerCommand::Cast { size } }
+    }
+    pub fn end_cast(id: u64) -> Request {
+	Request { id, command: ServerCommand::EndCast }
+    }
+    /// Ask what is on display, without changing it
+    pub fn status(id: u64) -> Request {
+	Request { id, command: ServerCommand::Status }
     }
+    /// Run a command that didn't come from a key
+    pub fn with_command(id: u64, command: ServerCommand) -> Request {
+	Request { id, command }
+    }
+    pub fn id(&self) -> u64 {
+	self.id
+    }
+    pub fn command(&self) -> &ServerCommand {
+	&self.command
+    }
+    pub fn to_bytes(&self, wire: Wire) -> Result<Vec<u8>> {
+	wire.pack(self)
+    }
+    pub fn from_bytes(bytes: Bytes, wire: Wire) -> Result<Request> {
+	wire.unpack(&bytes)
+    }
+}
+
+/// Outcome of a Request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Status {
+    // The command ran
+    Success,
+    // The command failed, the message says why
+    Failed,
+    // The client's role does not allow the command
+    PermissionDenied,
+    // The client sent too many commands of the kind, the message says
+    // when to try again
+    RateLimited,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Response {
+    // Id of the Request this is a reply to.
+    id: u64,
+    // Whether the command ran
+    status: Status,
     // Path of image currently on display. If Request updted image,
     // this will be the image display was updated to.
     path: Option<PathBuf>,
     // Success, Error, etc
     message: String,
-    // Image data in the case of request was fetch
-    bytes: Option<Vec<u8>>
+    // Where the image on display is in the list
+    position: Option<Position>,
 }
 
 impl Response {
-    pub fn new(path: Option<PathBuf>, bytes: Option<Vec<u8>>, message: &str) -> Response {
+    pub fn new(id: u64, status: Status, path: Option<PathBuf>, message: &str) -> Response {
 	let message = message.to_string();
-	Response { path, message, bytes }
+	Response { id, status, path, message, position: None }
     }
-    pub fn to_bytes(&self) -> Result<Vec<u8>> {
-	bincode::serialize(&self)
-            .map_err(|e| anyhow!("Serialization Error: {}", e))
+    pub fn with_position(self, position: Option<Position>) -> Response {
+	Response { position, ..self }
     }
-    pub fn from_bytes(bytes: Bytes) -> Result<Response> {
-	bincode::deserialize(&bytes)
-            .map_err(|e| anyhow!("Deserialization Error: {}", e))
+    pub fn id(&self) -> u64 {
+	self.id
+    }
+    pub fn status(&self) -> Status {
+	self.status
+    }
+    pub fn message(&self) -> &str {
+	&self.message
+    }
+    pub fn to_bytes(&self, wire: Wire) -> Result<Vec<u8>> {
+	// RateLimited came with version 10, older peers only know Failed
+	if self.status == Status::RateLimited && wire.version < 10 {
+	    return wire.pack(&Response { status: Status::Failed, ..self.clone() });
+	}
+	wire.pack(self)
+    }
+    pub fn from_bytes(bytes: Bytes, wire: Wire) -> Result<Response> {
+	wire.unpack(&bytes)
     }
     pub fn path(&self) -> Option<&Path> {
     	self.path.as_deref()
     }
+    pub fn position(&self) -> Option<Position> {
+	self.position
+    }
     // pub fn image_name(&self) -> &[u8] {
     // 	let path = self.path.unwrap();
     // 	path.file_stem().unwrap().as_bytes()
     // }
 }
 
+/// Index of the image on display among the `count` in the list,
+/// counting from 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub index: u64,
+    pub count: u64,
+}
+
+/// "Show the image at `index`", sent as a datagram for each arrow key
+/// by clients granted `Capabilities::DATAGRAMS`. Intents may be lost or
+/// overtaken, the server acts on the one with the highest `seq` and
+/// drops any older one arriving later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Intent {
+    pub seq: u64,
+    pub index: u64,
+}
+
+impl Intent {
+    pub fn to_bytes(self, wire: Wire) -> Result<Vec<u8>> {
+	wire.pack(&self)
+    }
+    pub fn from_bytes(bytes: Bytes, wire: Wire) -> Result<Intent> {
+	wire.unpack(&bytes)
+    }
+}
+
+/// Largest frame payload accepted on the wire. Anything bigger is
+/// treated as a protocol error rather than allocated.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Messages shorter than this are never compressed, it wouldn't pay
+const COMPRESS_MIN: usize = 512;
+
+/// zstd level messages and fetched files are compressed at, fast
+/// rather than small
+pub const ZSTD_LEVEL: i32 = 3;
+
+/// Low half of the leading byte of a packed message, telling whether
+/// the rest is compressed. The high half is the `Encoding`.
+const PACKED_PLAIN: u8 = 0;
+const PACKED_ZSTD: u8 = 1;
+
+/// How a side compresses the messages it sends after the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    // Send every message as it is
+    #[default]
+    Off,
+    // Compress messages large enough to gain from it. Only once both
+    // sides agreed to `Capabilities::COMPRESSION`.
+    Zstd,
+}
+
+impl Compression {
+    /// `message` compressed if it is worth it, and the packing saying
+    /// whether it is
+    fn compress(self, message: Vec<u8>) -> Result<(u8, Vec<u8>)> {
+	if self == Compression::Zstd && message.len() >= COMPRESS_MIN {
+	    let compressed = zstd::bulk::compress(&message, ZSTD_LEVEL)
+		.map_err(|e| anyhow!("Compression Error: {}", e))?;
+	    if compressed.len() < message.len() {
+		return Ok((PACKED_ZSTD, compressed));
+	    }
+	}
+	Ok((PACKED_PLAIN, message))
+    }
+}
+
+/// Serializer of the messages a side sends after the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    // bincode 1: compact, but follows the layout of the Rust types and
+    // is impractical to speak from other languages
+    #[default]
+    Bincode,
+    // JSON, for debugging and third party clients. Only once both
+    // sides agreed to `Capabilities::JSON`.
+    Json,
+    // CBOR, a binary encoding with a stable specification. Only with
+    // `Capabilities::CBOR`.
+    Cbor,
+}
+
+impl Encoding {
+    /// High half of the leading byte of a message in this encoding
+    fn tag(self) -> u8 {
+	match self {
+	    Encoding::Bincode => 0x00,
+	    Encoding::Json => 0x10,
+	    Encoding::Cbor => 0x20,
+	}
+    }
+    fn from_tag(tag: u8) -> Result<Encoding> {
+	match tag & 0xf0 {
+	    0x00 => Ok(Encoding::Bincode),
+	    0x10 => Ok(Encoding::Json),
+	    0x20 => Ok(Encoding::Cbor),
+	    tag => bail!("Deserialization Error: unknown encoding {}", tag >> 4),
+	}
+    }
+    fn serialize<T: Serialize>(self, message: &T) -> Result<Vec<u8>> {
+	match self {
+	    Encoding::Bincode => bincode::serialize(message)
+		.map_err(|e| anyhow!("Serialization Error: {}", e)),
+	    Encoding::Json => serde_json::to_vec(message)
+		.map_err(|e| anyhow!("Serialization Error: {}", e)),
+	    Encoding::Cbor => {
+		let mut bytes = Vec::new();
+		ciborium::into_writer(message, &mut bytes)
+		    .map_err(|e| anyhow!("Serialization Error: {}", e))?;
+		Ok(bytes)
+	    }
+	}
+    }
+    fn deserialize<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T> {
+	match self {
+	    Encoding::Bincode => bincode::deserialize(bytes)
+		.map_err(|e| anyhow!("Deserialization Error: {}", e)),
+	    Encoding::Json => serde_json::from_slice(bytes)
+		.map_err(|e| anyhow!("Deserialization Error: {}", e)),
+	    Encoding::Cbor => ciborium::from_reader(bytes)
+		.map_err(|e| anyhow!("Deserialization Error: {}", e)),
+	}
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	match self {
+	    Encoding::Bincode => write!(f, "bincode"),
+	    Encoding::Json => write!(f, "json"),
+	    Encoding::Cbor => write!(f, "cbor"),
+	}
+    }
+}
+
+/// How every message of a connection after the handshake is packed,
+/// requests and responses as well as events, stream headers, intents
+/// and the pairing exchange. Plain bincode unless the handshake agreed
+/// to something else, as older peers expect. Otherwise each starts with
+/// a byte telling how the rest is encoded and whether it is zstd
+/// compressed. Only `Hello` and `HelloReply` are always bincode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Wire {
+    pub encoding: Encoding,
+    pub compression: Compression,
+    // Protocol version agreed in the handshake
+    pub version: u16,
+}
+
+impl Default for Wire {
+    fn default() -> Wire {
+	Wire {
+	    encoding: Encoding::default(),
+	    compression: Compression::default(),
+	    version: PROTOCOL_VERSION,
+	}
+    }
+}
+
+impl Wire {
+    /// What a side may send with, given the agreed `version` and
+    /// `capabilities`
+    pub fn negotiated(version: u16, capabilities: Capabilities) -> Wire {
+	let encoding = if capabilities.contains(Capabilities::CBOR) {
+	    Encoding::Cbor
+	} else if capabilities.contains(Capabilities::JSON) {
+	    Encoding::Json
+	} else {
+	    Encoding::Bincode
+	};
+	let compression = match capabilities.contains(Capabilities::COMPRESSION) {
+	    true => Compression::Zstd,
+	    false => Compression::Off,
+	};
+	Wire { encoding, compression, version }
+    }
+    /// Whether messages start with the tag byte
+    fn tagged(self) -> bool {
+	self.encoding != Encoding::Bincode || self.compression != Compression::Off
+    }
+    /// `message` encoded, compressed if it is worth it, behind the tag
+    /// byte if there is one
+    fn pack<T: Serialize>(self, message: &T) -> Result<Vec<u8>> {
+	let bytes = self.encoding.serialize(message)?;
+	if !self.tagged() {
+	    return Ok(bytes);
+	}
+	let (packing, bytes) = self.compression.compress(bytes)?;
+	Ok([&[self.encoding.tag() | packing][..], &bytes].concat())
+    }
+    /// The message `pack` packed into `bytes`. A tagged one may use
+    /// any encoding, whatever this side sends with.
+    fn unpack<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T> {
+	if !self.tagged() {
+	    return self.encoding.deserialize(bytes);
+	}
+	let Some((&tag, message)) = bytes.split_first() else {
+	    bail!("Deserialization Error: empty message");
+	};
+	let encoding = Encoding::from_tag(tag)?;
+	let message = match tag & 0x0f {
+	    PACKED_PLAIN => Cow::Borrowed(message),
+	    // no bigger than a frame could have held
+	    PACKED_ZSTD => zstd::bulk::decompress(message, MAX_FRAME_SIZE)
+		.map(Cow::Owned)
+		.map_err(|e| anyhow!("Deserialization Error: bad compressed message: {}", e))?,
+	    packing => bail!("Deserialization Error: unknown packing {}", packing),
+	};
+	encoding.deserialize(&message)
+    }
+}
+
+/// Write `payload` as a single frame: a big-endian u32 length prefix
+/// followed by the payload bytes.
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+	bail!("Frame Error: {} bytes exceeds limit of {}", payload.len(), MAX_FRAME_SIZE);
+    }
+    writer.write_u32(payload.len() as u32).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read a single frame written by `write_frame`. Returns `None` if the
+/// stream finished cleanly on a frame boundary.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Bytes>> {
+    let mut prefix = [0u8; 4];
+    let mut filled = 0;
+    while filled < prefix.len() {
+	match reader.read(&mut prefix[filled..]).await? {
+	    0 if filled == 0 => return Ok(None),
+	    0 => bail!("Frame Error: stream closed inside length prefix"),
+	    n => filled += n,
+	}
+    }
+    let len = u32::from_be_bytes(prefix) as usize;
+    if len > MAX_FRAME_SIZE {
+	bail!("Frame Error: {} bytes exceeds limit of {}", len, MAX_FRAME_SIZE);
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await.map_err(|e| match e.kind() {
+	ErrorKind::UnexpectedEof => anyhow!("Frame Error: stream closed inside payload"),
+	_ => anyhow!("Frame Error: {}", e),
+    })?;
+    Ok(Some(payload.into()))
+}
+
+/// First frame of every unidirectional stream the server opens, saying
+/// what the rest of the stream carries.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StreamHeader {
+    // ServerEvent frames for as long as the connection lasts
+    Events,
+    // The requested range of a fetched file, unframed
+    Download(FileInfo),
+    // The requested range as a single zstd stream. Only once both
+    // sides agreed to `Capabilities::COMPRESSION`.
+    ZstdDownload(FileInfo),
+}
+
+impl StreamHeader {
+    pub fn to_bytes(&self, wire: Wire) -> Result<Vec<u8>> {
+	wire.pack(self)
+    }
+    pub fn from_bytes(bytes: Bytes, wire: Wire) -> Result<StreamHeader> {
+	wire.unpack(&bytes)
+    }
+}
+
+/// A file sent to the client in answer to a Fetch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileInfo {
+    // Id of the Fetch request
+    pub id: u64,
+    // File name, without directories
+    pub name: String,
+    // Size of the whole file
+    pub size: u64,
+    // BLAKE3 hash of the whole file
+    pub hash: [u8; 32],
+    // Position in the file of the first byte following the header
+    pub offset: u64,
+    // Bytes following the header, before any compression
+    pub length: u64,
+}
+
+/// Display changes pushed to every client that negotiated
+/// `Capabilities::EVENTS`, whoever caused them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ServerEvent {
+    // A different image is on display, and where it is in the list
+    ImageChanged(PathBuf, Option<Position>),
+    // Rotation of the image on display, in degrees
+    Rotated(f64),
+    // Fullscreen was switched on or off
+    Fullscreen(bool),
+    // Pageant mode was switched on or off
+    Pageant(bool),
+    // Number of images, after some were uploaded or unsupported files
+    // dropped
+    ListChanged(usize),
+    // A cast image was put on display or taken off
+    Cast(bool),
+}
+
+impl ServerEvent {
+    pub fn to_bytes(&self, wire: Wire) -> Result<Vec<u8>> {
+	wire.pack(self)
+    }
+    pub fn from_bytes(bytes: Bytes, wire: Wire) -> Result<ServerEvent> {
+	wire.unpack(&bytes)
+    }
+}
+
+impl fmt::Display for ServerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	let on_off = |on: &bool| if *on { "on" } else { "off" };
+	match self {
+	    Self::ImageChanged(path, _) => write!(f, "{}", path.display()),
+	    Self::Rotated(degrees) => write!(f, "rotated {} degrees", degrees),
+	    Self::Fullscreen(on) => write!(f, "fullscreen {}", on_off(on)),
+	    Self::Pageant(on) => write!(f, "pageant {}", on_off(on)),
+	    Self::ListChanged(count) => write!(f, "{} images", count),
+	    Self::Cast(on) => write!(f, "cast {}", on_off(on)),
+	}
+    }
+}
+
 /// Possible commands to execute on the Server.
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerCommand {
     // Go back to the last image
     Prev,
@@ -80,8 +714,19 @@ pub enum ServerCommand {
     Rotate,
     // Update Image ever second
     Pageant,
-    // Download image to client
-    Fetch
+    // Download image to client, from `offset` for `length` bytes or
+    // to the end
+    Fetch { offset: u64, length: Option<u64> },
+    // Add the image whose bytes follow the request on its stream
+    Upload { name: String, size: u64, show: bool },
+    // Show the image whose bytes follow the request, without storing it
+    Cast { size: u64 },
+    // Go back to the image underneath the cast one
+    EndCast,
+    // Nothing, the response names the image on display
+    Status,
+    // Show the image at `index` in the list, counting from 0
+    Goto { index: u64 },
 }
 
 impl ServerCommand {
@@ -89,7 +734,7 @@ impl ServerCommand {
 	match code {
 	    KeyCode::Char('f') => Some(Self::Fullscreen),
 	    KeyCode::Char('r') => Some(Self::Rotate),
-	    KeyCode::Char('s') => Some(Self::Fetch),
+	    KeyCode::Char('s') => Some(Self::Fetch { offset: 0, length: None }),
 	    KeyCode::Char('p') | KeyCode::Space => Some(Self::Pageant),
 	    KeyCode::ArrowRight => Some(Self::Next),
 	    KeyCode::ArrowLeft => Some(Self::Prev),
@@ -112,7 +757,12 @@ impl fmt::Display for ServerCommand {
 	match self {
 	    Self::Fullscreen => write!(f, "Fullscreen"),
 	    Self::Rotate => write!(f, "Rotate"),
-	    Self::Fetch => write!(f, "Fetch"),
+	    Self::Fetch { .. } => write!(f, "Fetch"),
+	    Self::Upload { .. } => write!(f, "Upload"),
+	    Self::Cast { .. } => write!(f, "Cast"),
+	    Self::EndCast => write!(f, "EndCast"),
+	    Self::Status => write!(f, "Status"),
+	    Self::Goto { .. } => write!(f, "Goto"),
 	    Self::Pageant => write!(f, "Pageant"),
 	    Self::Next => write!(f, "Next"),
 	    Self::Prev => write!(f, "Previous"),
@@ -126,14 +776,390 @@ mod tests {
     use std::path::Path;
     use super::*;
 
+    const ZSTD: Wire = Wire { encoding: Encoding::Bincode, compression: Compression::Zstd, version: PROTOCOL_VERSION };
+    const ENCODINGS: [Encoding; 3] = [Encoding::Bincode, Encoding::Json, Encoding::Cbor];
+
     #[test]
     fn test_serialize_response() -> Result<()> {
 	let path = Path::new("/foo/bar.jpg").to_path_buf();
-	let resp = Response::new(Some(path), None, "Success");
-	let bytes = resp.to_bytes()?;
-	let decoded = Response::from_bytes(bytes.into())?;
-	assert_eq!(resp.path, decoded.path);
+	let position = Some(Position { index: 0, count: 2 });
+	let resp = Response::new(7, Status::Success, Some(path), "Success").with_position(position);
+	for encoding in ENCODINGS {
+	    let wire = Wire { encoding, ..Wire::default() };
+	    let decoded = Response::from_bytes(resp.to_bytes(wire)?.into(), wire)?;
+	    assert_eq!(resp.path, decoded.path, "{}", encoding);
+	    assert_eq!(decoded.id(), 7);
+	    assert_eq!(decoded.status(), Status::Success);
+	    assert_eq!(decoded.message(), "Success");
+	    assert_eq!(decoded.position(), position);
+	}
+	Ok(())
+    }
+
+    #[test]
+    fn test_encodings() -> Result<()> {
+	let json = Wire { encoding: Encoding::Json, ..Wire::default() };
+	let cbor = Wire { encoding: Encoding::Cbor, ..ZSTD };
+	// JSON is readable past the tag byte
+	let req = Request::fetch(3, 1 << 32, None);
+	let bytes = req.to_bytes(json)?;
+	assert_eq!(bytes[0], 0x10);
+	assert_eq!(&bytes[1..], br#"{"id":3,"command":{"Fetch":{"offset":4294967296,"length":null}}}"#);
+	assert!(matches!(Request::from_bytes(bytes.into(), json)?.command(),
+			 ServerCommand::Fetch { offset, length: None } if *offset == 1 << 32));
+
+	// and compresses like any other
+	let long = format!("/photos/{}.jpg", "holiday/".repeat(100));
+	let event = ServerEvent::ImageChanged(PathBuf::from(&long), None);
+	let bytes = event.to_bytes(cbor)?;
+	assert_eq!(bytes[0], 0x20 | PACKED_ZSTD);
+	let decoded = ServerEvent::from_bytes(bytes.into(), cbor)?;
+	assert!(matches!(decoded, ServerEvent::ImageChanged(p, None) if p == Path::new(&long)));
+
+	let header = StreamHeader::Download(FileInfo {
+	    id: 3,
+	    name: "bar.jpg".to_string(),
+	    size: 1 << 33,
+	    hash: [7; 32],
+	    offset: 0,
+	    length: 1 << 33,
+	});
+	for encoding in ENCODINGS {
+	    let wire = Wire { encoding, ..Wire::default() };
+	    let bytes = header.to_bytes(wire)?;
+	    assert_eq!(StreamHeader::from_bytes(bytes.into(), wire)?, header, "{}", encoding);
+	}
+	assert!(Response::from_bytes(Bytes::from_static(&[0x10, b'{']), json).is_err());
+	assert!(Response::from_bytes(Bytes::from_static(&[0x30, 0]), json).is_err());
+
+	// CBOR wins if a client asks for both
+	assert_eq!(Wire::negotiated(PROTOCOL_VERSION, Capabilities::SUPPORTED).encoding, Encoding::Cbor);
+	assert_eq!(Wire::negotiated(PROTOCOL_VERSION, Capabilities::JSON).encoding, Encoding::Json);
+	assert_eq!(Wire::negotiated(PROTOCOL_VERSION, Capabilities::COMPRESSION), ZSTD);
+	Ok(())
+    }
+
+    #[test]
+    fn test_compression() -> Result<()> {
+	// a response worth compressing, and one too small to be
+	let long = format!("/photos/{}.jpg", "holiday/".repeat(100));
+	let resp = Response::new(7, Status::Success, Some(PathBuf::from(&long)), "Success");
+	let plain = resp.to_bytes(Wire::default())?;
+	let packed = resp.to_bytes(ZSTD)?;
+	assert_eq!(packed[0], PACKED_ZSTD);
+	assert!(packed.len() < plain.len() / 4, "{} of {} bytes", packed.len(), plain.len());
+	let decoded = Response::from_bytes(packed.into(), ZSTD)?;
+	assert_eq!(decoded.path(), Some(Path::new(&long)));
+	assert_eq!(Response::from_bytes(plain.into(), Wire::default())?.path(), Some(Path::new(&long)));
+
+	let req = Request::new(42, KeyCode::ArrowRight).expect("server command");
+	let packed = req.to_bytes(ZSTD)?;
+	assert_eq!(packed[0], PACKED_PLAIN);
+	assert_eq!(packed[1..], req.to_bytes(Wire::default())?);
+	assert!(matches!(Request::from_bytes(packed.into(), ZSTD)?.command(), ServerCommand::Next));
+
+	let event = ServerEvent::ImageChanged(PathBuf::from(&long), None);
+	let decoded = ServerEvent::from_bytes(event.to_bytes(ZSTD)?.into(), ZSTD)?;
+	assert!(matches!(decoded, ServerEvent::ImageChanged(p, None) if p == Path::new(&long)));
+
+	assert!(Response::from_bytes(Bytes::from_static(&[PACKED_ZSTD, 1, 2, 3]), ZSTD).is_err());
+	assert!(Response::from_bytes(Bytes::from_static(&[9]), ZSTD).is_err());
+	assert!(Response::from_bytes(Bytes::new(), ZSTD).is_err());
+	Ok(())
+    }
+
+    #[test]
+    fn test_serialize_event() -> Result<()> {
+	let position = Some(Position { index: 3, count: 40 });
+	let event = ServerEvent::ImageChanged(PathBuf::from("/foo/bar.jpg"), position);
+	let decoded = ServerEvent::from_bytes(event.to_bytes(Wire::default())?.into(), Wire::default())?;
+	assert!(matches!(decoded, ServerEvent::ImageChanged(p, q) if p == Path::new("/foo/bar.jpg") && q == position));
+	Ok(())
+    }
+
+    #[test]
+    fn test_serialize_intent() -> Result<()> {
+	let intent = Intent { seq: 12, index: 1 << 33 };
+	let wire = Wire::default();
+	assert_eq!(Intent::from_bytes(intent.to_bytes(wire)?.into(), wire)?, intent);
+	assert!(Intent::from_bytes(Bytes::from_static(b"short"), wire).is_err());
+	let json = Wire { encoding: Encoding::Json, ..wire };
+	let bytes = intent.to_bytes(json)?;
+	assert_eq!(&bytes[..], b"\x10{\"seq\":12,\"index\":8589934592}");
+	assert_eq!(Intent::from_bytes(bytes.into(), json)?, intent);
+	Ok(())
+    }
+
+    #[test]
+    fn test_serialize_pairing() -> Result<()> {
+	let request = PairRequest { pin: "042917".to_string(), name: "kitchen".to_string(), role: Role::Viewer };
+	let reply = PairReply::Paired { cert: "CERT".to_string(), key: "KEY".to_string(), role: Role::Viewer };
+	for encoding in ENCODINGS {
+	    let wire = Wire { encoding, ..Wire::default() };
+	    assert_eq!(PairRequest::from_bytes(request.to_bytes(wire)?.into(), wire)?, request, "{}", encoding);
+	    assert_eq!(PairReply::from_bytes(reply.to_bytes(wire)?.into(), wire)?, reply, "{}", encoding);
+	}
+	let json = Wire { encoding: Encoding::Json, ..Wire::default() };
+	assert_eq!(&PairReply::PinShown(60).to_bytes(json)?[..], b"\x10{\"PinShown\":60}");
+	Ok(())
+    }
+
+    #[test]
+    fn test_serialize_stream_header() -> Result<()> {
+	let info = FileInfo {
+	    id: 3,
+	    name: "bar.jpg".to_string(),
+	    size: 1 << 33,
+	    hash: [7; 32],
+	    offset: 1 << 32,
+	    length: 1 << 32,
+	};
+	let header = StreamHeader::Download(info.clone());
+	assert_eq!(StreamHeader::from_bytes(header.to_bytes(Wire::default())?.into(), Wire::default())?, header);
+	let header = StreamHeader::Events;
+	assert_eq!(StreamHeader::from_bytes(header.to_bytes(Wire::default())?.into(), Wire::default())?, header);
+	let header = StreamHeader::ZstdDownload(info);
+	assert_eq!(StreamHeader::from_bytes(header.to_bytes(ZSTD)?.into(), ZSTD)?, header);
+	Ok(())
+    }
+
+    #[test]
+    fn test_capabilities() {
+	let both = Capabilities::SUPPORTED.intersection(Capabilities::EVENTS);
+	assert!(both.contains(Capabilities::EVENTS));
+	assert!(!Capabilities::default().contains(Capabilities::EVENTS));
+	let streams_only = Capabilities::SUPPORTED.without(Capabilities::DATAGRAMS);
+	assert!(streams_only.contains(Capabilities::EVENTS));
+	assert!(!streams_only.contains(Capabilities::DATAGRAMS));
+    }
+
+    #[test]
+    fn test_serialize_request() -> Result<()> {
+	let req = Request::new(42, KeyCode::ArrowRight).expect("server command");
+	let decoded = Request::from_bytes(req.to_bytes(Wire::default())?.into(), Wire::default())?;
+	assert_eq!(decoded.id(), 42);
+	assert!(matches!(decoded.command(), ServerCommand::Next));
+
+	let req = Request::fetch(43, 1 << 32, Some(512));
+	for encoding in ENCODINGS {
+	    let wire = Wire { encoding, ..Wire::default() };
+	    let decoded = Request::from_bytes(req.to_bytes(wire)?.into(), wire)?;
+	    assert_eq!(decoded.id(), 43);
+	    assert!(matches!(decoded.command(),
+			     ServerCommand::Fetch { offset, length: Some(512) } if *offset == 1 << 32));
+	}
+	Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_frame_roundtrip() -> Result<()> {
+	let (mut client, mut server) = tokio::io::duplex(64);
+	let large = vec![7u8; 10_000];
+	let writer = tokio::spawn(async move {
+	    write_frame(&mut client, b"first").await?;
+	    write_frame(&mut client, &large).await?;
+	    write_frame(&mut client, b"").await?;
+	    Ok::<_, anyhow::Error>(())
+	});
+	assert_eq!(read_frame(&mut server).await?.as_deref(), Some(&b"first"[..]));
+	assert_eq!(read_frame(&mut server).await?.map(|b| b.len()), Some(10_000));
+	assert_eq!(read_frame(&mut server).await?.as_deref(), Some(&b""[..]));
+	writer.await??;
+	assert!(read_frame(&mut server).await?.is_none());
+	Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_frame_split_chunks() -> Result<()> {
+	let resp = Response::new(1, Status::Success, Some(Path::new("/foo/bar.jpg").to_path_buf()), "Success");
+	let mut wire = Vec::new();
+	write_frame(&mut wire, &resp.to_bytes(Wire::default())?).await?;
+	write_frame(&mut wire, &resp.to_bytes(Wire::default())?).await?;
+	// deliver the two frames one byte at a time
+	let mut reader = trickle_reader(wire);
+	for _ in 0..2 {
+	    let bytes = read_frame(&mut reader).await?.expect("frame");
+	    assert_eq!(Response::from_bytes(bytes, Wire::default())?.path, resp.path);
+	}
+	assert!(read_frame(&mut reader).await?.is_none());
+	Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_frame_limits() -> Result<()> {
+	let oversized = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes();
+	assert!(read_frame(&mut &oversized[..]).await.is_err());
+	let truncated = [0u8, 0, 0, 8, 1, 2];
+	assert!(read_frame(&mut &truncated[..]).await.is_err());
+	let partial_prefix = [0u8, 0];
+	assert!(read_frame(&mut &partial_prefix[..]).await.is_err());
+	Ok(())
+    }
+
+    /// Reader that yields its contents one byte per read call.
+    fn trickle_reader(data: Vec<u8>) -> impl AsyncRead + Unpin {
+	let (mut tx, rx) = tokio::io::duplex(1);
+	tokio::spawn(async move { tx.write_all(&data).await });
+	rx
+    }
+
+    /// Encode `payload` as a frame, the way it appears on the wire.
+    async fn framed(payload: Vec<u8>) -> Result<Vec<u8>> {
+	let mut wire = Vec::new();
+	write_frame(&mut wire, &payload).await?;
+	Ok(wire)
+    }
+
+    /// Strip the frame from a golden fixture.
+    async fn unframed(mut fixture: &[u8]) -> Result<Bytes> {
+	let bytes = read_frame(&mut fixture).await?.expect("frame");
+	assert!(fixture.is_empty(), "trailing bytes after frame");
+	Ok(bytes)
+    }
+
+    #[tokio::test]
+    async fn test_golden_hello() -> Result<()> {
+	let fixture = include_bytes!("../fixtures/hello_v11.bin");
+	let hello = Hello::new(Capabilities::default());
+	assert_eq!(framed(hello.to_bytes()?).await?, fixture);
+	assert_eq!(Hello::from_bytes(unframed(fixture).await?)?, hello);
+
+	let fixture = include_bytes!("../fixtures/hello_reply_v11.bin");
+	let reply = HelloReply::Accept { version: 11, capabilities: Capabilities::default() };
+	assert_eq!(framed(reply.to_bytes()?).await?, fixture);
+	assert_eq!(HelloReply::from_bytes(unframed(fixture).await?)?, reply);
+
+	let fixture = include_bytes!("../fixtures/pair_reply_v11.bin");
+	let paired = PairReply::Paired { cert: "CERT".to_string(), key: "KEY".to_string(), role: Role::Controller };
+	assert_eq!(framed(paired.to_bytes(Wire::default())?).await?, fixture);
+	assert_eq!(PairReply::from_bytes(unframed(fixture).await?, Wire::default())?, paired);
+	Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_golden_v10() -> Result<()> {
+	// a version 10 client is still served, it isn't told its role
+	let fixture = include_bytes!("../fixtures/hello_v10.bin");
+	let hello = Hello::from_bytes(unframed(fixture).await?)?;
+	let reply = HelloReply::Accept { version: 10, capabilities: Capabilities::default() };
+	assert_eq!(hello.reply(), reply);
+	let fixture = include_bytes!("../fixtures/hello_reply_v10.bin");
+	assert_eq!(framed(reply.to_bytes()?).await?, fixture);
+
+	let wire = Wire::negotiated(10, Capabilities::default());
+	let fixture = include_bytes!("../fixtures/pair_reply_v10.bin");
+	let paired = PairReply::Paired { cert: "CERT".to_string(), key: "KEY".to_string(), role: Role::Controller };
+	assert_eq!(framed(paired.to_bytes(wire)?).await?, fixture);
+	Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_golden_v9() -> Result<()> {
+	// the v9 fixtures were written by a version 9 build, a version 9
+	// peer is still served and must get exactly what it used to
+	let wire = Wire::negotiated(9, Capabilities::default());
+	let fixture = include_bytes!("../fixtures/hello_v9.bin");
+	let hello = Hello::from_bytes(unframed(fixture).await?)?;
+	let reply = HelloReply::Accept { version: 9, capabilities: Capabilities::default() };
+	assert_eq!(hello.reply(), reply);
+	let fixture = include_bytes!("../fixtures/hello_reply_v9.bin");
+	assert_eq!(framed(reply.to_bytes()?).await?, fixture);
+
+	let fixture = include_bytes!("../fixtures/request_v9.bin");
+	let decoded = Request::from_bytes(unframed(fixture).await?, wire)?;
+	assert_eq!(decoded.id(), 1);
+	assert!(matches!(decoded.command(), ServerCommand::Next));
+
+	let fixture = include_bytes!("../fixtures/response_v9.bin");
+	let resp = Response::new(1, Status::Success, Some(PathBuf::from("/photos/a.jpg")), "Success");
+	assert_eq!(framed(resp.to_bytes(wire)?).await?, fixture);
+
+	let fixture = include_bytes!("../fixtures/event_v9.bin");
+	let position = Some(Position { index: 3, count: 12 });
+	let event = ServerEvent::ImageChanged(PathBuf::from("/photos/a.jpg"), position);
+	assert_eq!(framed(event.to_bytes(wire)?).await?, fixture);
+
+	let fixture = include_bytes!("../fixtures/stream_header_v9.bin");
+	let info = FileInfo {
+	    id: 7,
+	    name: "a.jpg".to_string(),
+	    size: 2048,
+	    hash: [0xab; 32],
+	    offset: 1024,
+	    length: 1024,
+	};
+	assert_eq!(framed(StreamHeader::Download(info).to_bytes(wire)?).await?, fixture);
+
+	let fixture = include_bytes!("../fixtures/pair_reply_v9.bin");
+	let paired = PairReply::Paired { cert: "CERT".to_string(), key: "KEY".to_string(), role: Role::Viewer };
+	assert_eq!(framed(paired.to_bytes(wire)?).await?, fixture);
+
+	// RateLimited came with version 10
+	let limited = Response::new(2, Status::RateLimited, None, "try again in 1s");
+	let decoded = Response::from_bytes(limited.to_bytes(wire)?.into(), wire)?;
+	assert_eq!(decoded.status(), Status::Failed);
+	assert_eq!(decoded.message(), "try again in 1s");
+	let decoded = Response::from_bytes(limited.to_bytes(Wire::default())?.into(), Wire::default())?;
+	assert_eq!(decoded.status(), Status::RateLimited);
 	Ok(())
     }
 
+    #[tokio::test]
+    async fn test_golden_hello_v1_rejected() -> Result<()> {
+	// a version 1 client is told it is too old rather than misread
+	let fixture = include_bytes!("../fixtures/hello_v1.bin");
+	let hello = Hello::from_bytes(unframed(fixture).await?)?;
+	match hello.reply() {
+	    HelloReply::Reject { version, reason } => {
+		assert_eq!(version, PROTOCOL_VERSION);
+		assert!(reason.contains("too old"), "{}", reason);
+	    }
+	    reply => panic!("version 1 accepted: {:?}", reply),
+	}
+	Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_golden_request_response() -> Result<()> {
+	let fixture = include_bytes!("../fixtures/request_v10.bin");
+	let req = Request::new(1, KeyCode::ArrowRight).expect("server command");
+	assert_eq!(framed(req.to_bytes(Wire::default())?).await?, fixture);
+	let decoded = Request::from_bytes(unframed(fixture).await?, Wire::default())?;
+	assert_eq!(decoded.id(), 1);
+	assert!(matches!(decoded.command(), ServerCommand::Next));
+
+	let fixture = include_bytes!("../fixtures/response_v10.bin");
+	let resp = Response::new(1, Status::Success, Some(PathBuf::from("/photos/a.jpg")), "Success");
+	assert_eq!(framed(resp.to_bytes(Wire::default())?).await?, fixture);
+	let decoded = Response::from_bytes(unframed(fixture).await?, Wire::default())?;
+	assert_eq!(decoded.id(), 1);
+	assert_eq!(decoded.path(), Some(Path::new("/photos/a.jpg")));
+	assert_eq!(decoded.status(), Status::Success);
+	Ok(())
+    }
+
+    #[test]
+    fn test_role_permits() -> Result<()> {
+	assert!(Role::Viewer.permits(&ServerCommand::Fetch { offset: 0, length: None }));
+	assert!(Role::Viewer.permits(&ServerCommand::Status));
+	assert!(!Role::Viewer.permits(&ServerCommand::Next));
+	assert!(!Role::Viewer.permits(&ServerCommand::Rotate));
+	assert!(!Role::Viewer.permits(&ServerCommand::Goto { index: 0 }));
+	assert!(!Role::Viewer.permits(&ServerCommand::Upload { name: "a.jpg".to_string(), size: 1, show: false }));
+	assert!(!Role::Viewer.permits(&ServerCommand::Cast { size: 1 }));
+	assert!(Role::Controller.permits(&ServerCommand::Next));
+	assert_eq!("viewer".parse::<Role>()?, Role::Viewer);
+	assert!("admin".parse::<Role>().is_err());
+	Ok(())
+    }
+
+    #[test]
+    fn test_hello_version_check() {
+	let hello = |version| Hello { magic: HELLO_MAGIC, version, capabilities: Capabilities::SUPPORTED };
+	assert!(matches!(hello(PROTOCOL_VERSION).reply(), HelloReply::Accept { .. }));
+	assert!(matches!(hello(MIN_PROTOCOL_VERSION - 1).reply(), HelloReply::Reject { .. }));
+	assert!(matches!(hello(PROTOCOL_VERSION + 1).reply(), HelloReply::Reject { .. }));
+	let garbage = Hello { magic: *b"GET ", ..hello(PROTOCOL_VERSION) };
+	assert!(matches!(garbage.reply(), HelloReply::Reject { .. }));
+    }
 }
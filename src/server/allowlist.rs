@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use s2n_quic::provider::event::{
+    events::{ConnectionInfo, ConnectionMeta, TlsExporterReady},
+    Subscriber,
+};
+
+use crate::model::Role;
+use crate::tls::fingerprint;
+
+/// Client certificate fingerprints allowed to connect, read from a file
+/// with one `fingerprint [name] [role]` per line. The role is `viewer`
+/// or `controller`, the default. Lines starting with `#` are comments.
+#[derive(Debug)]
+pub struct Allowlist {
+    path: PathBuf,
+    clients: BTreeMap<String, Client>,
+}
+
+/// An allowed client
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Client {
+    name: String,
+    role: Role,
+}
+
+impl Allowlist {
+    /// Read the allowlist at `path`. A missing file lets nobody in
+    /// until clients are paired.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(anyhow!("Allowlist Error: read {}: {}", path.display(), e)),
+        };
+        Ok(Self::parse(path, &text))
+    }
+    fn parse(path: &Path, text: &str) -> Self {
+        let clients = text
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| {
+                let mut words: Vec<&str> = l.split_whitespace().collect();
+                let fingerprint = words.remove(0).to_lowercase();
+                let role = match words.last().map(|w| w.parse::<Role>()) {
+                    Some(Ok(role)) => {
+                        words.pop();
+                        role
+                    }
+                    _ => Role::Controller,
+                };
+                let name = words.join(" ");
+                (fingerprint, Client { name, role })
+            })
+            .collect();
+        let path = path.to_path_buf();
+        Self { path, clients }
+    }
+    /// Check the certificate a client presented. Returns the client's
+    /// role, or why it may not connect.
+    pub fn check(&self, fingerprint: Option<&str>) -> Result<Role, String> {
+        let Some(fp) = fingerprint else {
+            return Err("pairing required (no client certificate)".to_string());
+        };
+        match self.clients.get(fp) {
+            Some(client) => Ok(client.role),
+            None => Err(format!("pairing required (certificate {} is not paired)", fp)),
+        }
+    }
+    /// Let the client with `fingerprint` in from now on, appending it to
+    /// the file.
+    pub fn add(&mut self, fingerprint: &str, name: &str, role: Role) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| anyhow!("Allowlist Error: open {}: {}", self.path.display(), e))?;
+        writeln!(file, "{} {} {}", fingerprint, name, role)?;
+        let client = Client { name: name.to_string(), role };
+        self.clients.insert(fingerprint.to_string(), client);
+        Ok(())
+    }
+    /// Name the allowlist gives the client with this fingerprint
+    pub fn name(&self, fingerprint: &str) -> Option<&str> {
+        self.clients.get(fingerprint).map(|c| c.name.as_str())
+    }
+}
+
+/// Connection context recording the fingerprint of the certificate the
+/// client presented, if any.
+#[derive(Debug, Default)]
+pub struct PeerCertificate {
+    pub fingerprint: Option<String>,
+}
+
+/// Event subscriber filling in each connection's `PeerCertificate`
+#[derive(Debug, Default)]
+pub struct PeerCertificates;
+
+impl Subscriber for PeerCertificates {
+    type ConnectionContext = PeerCertificate;
+
+    fn create_connection_context(
+        &mut self,
+        _meta: &ConnectionMeta,
+        _info: &ConnectionInfo,
+    ) -> Self::ConnectionContext {
+        PeerCertificate::default()
+    }
+
+    fn on_tls_exporter_ready(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        event: &TlsExporterReady,
+    ) {
+        context.fingerprint = event
+            .session
+            .peer_cert_chain_der()
+            .ok()
+            .and_then(|chain| chain.first().map(|der| fingerprint(der)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowlist() {
+        let allowlist = Allowlist::parse(
+            Path::new("allowlist"),
+            "# living room\n\
+             AA:BB  wall tablet viewer\n\
+             \n\
+             cc:dd\n",
+        );
+        assert_eq!(allowlist.check(Some("aa:bb")), Ok(Role::Viewer));
+        assert_eq!(allowlist.check(Some("cc:dd")), Ok(Role::Controller));
+        assert_eq!(allowlist.name("aa:bb"), Some("wall tablet"));
+        assert!(allowlist.check(Some("ee:ff")).is_err());
+        assert!(allowlist.check(None).is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use crossbeam_channel::Sender;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, info, warn};
+
+use crate::model::{Request, Role, ServerCommand, Status};
+use crate::server::controller::Envelope;
+use crate::server::unix_service::bind;
+use crate::transport::ACCEPT_BACKOFF;
+
+/// Commands scripts on the display box may send, for error messages
+const COMMANDS: &str = "next, prev, goto N or status";
+
+/// A Unix socket taking one text command per line from scripts on this
+/// machine, answered with `ok <image on display>` or `error <reason>`.
+/// Whoever may open the socket controls the display.
+pub struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    tx_req: Sender<Envelope>,
+}
+
+impl ControlSocket {
+    /// Listen at `path` and pass commands on through `tx_req`.
+    pub fn new(path: &Path, tx_req: Sender<Envelope>) -> Result<ControlSocket> {
+        let listener = bind(path)?;
+        info!("control socket {}", path.display());
+        Ok(ControlSocket {
+            listener,
+            path: path.to_path_buf(),
+            tx_req,
+        })
+    }
+
+    pub fn listen_task(self) {
+        tokio::spawn(async move {
+            loop {
+                let stream = match self.listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("control socket accept failed: {}", e);
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+                let fut = serve(stream, self.tx_req.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = fut.await {
+                        debug!("control connection failed: {}", e);
+                    }
+                });
+            }
+        });
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Answer each command on `stream` in turn until it closes.
+async fn serve(stream: UnixStream, tx_req: Sender<Envelope>) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    let mut id = 0;
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let answer = match parse(&line) {
+            Ok(command) => {
+                let request = Request::with_command(id, command);
+                id += 1;
+                let response = Envelope::send(&tx_req, request, Role::Controller).await?;
+                match (response.status(), response.path()) {
+                    (Status::Success, Some(path)) => format!("ok {}", path.display()),
+                    (Status::Success, None) => "ok".to_string(),
+                    _ => format!("error {}", response.message().trim_start_matches("Error: ")),
+                }
+            }
+            Err(e) => format!("error {}", e),
+        };
+        write.write_all(format!("{}\n", answer).as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// The command a line of text stands for. `goto` counts images from 1.
+fn parse(line: &str) -> Result<ServerCommand> {
+    let mut words = line.split_whitespace();
+    let command = match (words.next(), words.next()) {
+        (Some("next"), None) => ServerCommand::Next,
+        (Some("prev"), None) => ServerCommand::Prev,
+        (Some("status"), None) => ServerCommand::Status,
+        (Some("goto"), Some(position)) => {
+            let index = match position.parse::<u64>() {
+                Ok(position) if position > 0 => position - 1,
+                _ => bail!("goto takes an image number from 1, not {}", position),
+            };
+            ServerCommand::Goto { index }
+        }
+        _ => bail!("unknown command {:?}, expected {}", line.trim(), COMMANDS),
+    };
+    if words.next().is_some() {
+        bail!("unknown command {:?}, expected {}", line.trim(), COMMANDS);
+    }
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert!(matches!(parse("next"), Ok(ServerCommand::Next)));
+        assert!(matches!(parse(" prev \n"), Ok(ServerCommand::Prev)));
+        assert!(matches!(parse("status"), Ok(ServerCommand::Status)));
+        assert!(matches!(parse("goto 12"), Ok(ServerCommand::Goto { index: 11 })));
+        assert!(parse("goto 0").is_err());
+        assert!(parse("goto twelve").is_err());
+        assert!(parse("goto").is_err());
+        assert!(parse("next 2").is_err());
+        assert!(parse("rm -rf").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socket_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let (tx_req, _rx_req) = crossbeam_channel::unbounded();
+        let socket = ControlSocket::new(&path, tx_req).unwrap();
+        assert!(path.exists());
+        drop(socket);
+        assert!(!path.exists());
+    }
+}
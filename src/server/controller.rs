@@ -1,44 +1,107 @@
 use std::{
     ffi::OsStr,
-    path::{Path, PathBuf},
+    fmt,
+    path::Path,
     sync::{Arc, Mutex},
+    time::Instant,
 };
 
 use anyhow::{anyhow, Result};
-use bytes::Bytes;
 use crossbeam_channel::{Receiver, Sender};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use tokio::sync::{broadcast, mpsc, mpsc::UnboundedSender};
 use tracing::debug;
 
 use crate::{
-    model::{Request, Response, ServerCommand},
+    model::{Position, Request, Response, Role, ServerCommand, ServerEvent, Status},
+    server::metrics::metrics,
     server::navigator::Navigator,
     server::pageant::PageantMode,
+    server::pairing::Pairing,
     server::window::Window,
 };
 
+/// A decoded request along with the channel its response is sent back
+/// on. Each client stream holds its own reply channel so responses
+/// can't cross between connections.
+pub struct Envelope {
+    pub request: Request,
+    /// Role of the client that sent the request
+    pub role: Role,
+    pub reply: UnboundedSender<Response>,
+    /// Image that followed a Cast request on its stream
+    pub data: Option<Vec<u8>>,
+}
+
+impl Envelope {
+    /// Pass `request` on to the controller for a client with `role`,
+    /// and wait for its response.
+    pub async fn send(tx_req: &Sender<Envelope>, request: Request, role: Role) -> Result<Response> {
+        let (reply, mut responses) = mpsc::unbounded_channel();
+        let envelope = Envelope {
+            request,
+            role,
+            reply,
+            data: None,
+        };
+        tx_req
+            .send(envelope)
+            .map_err(|e| anyhow!("control reciever closed: {}", e))?;
+        responses.recv().await.ok_or(anyhow!("controller went away"))
+    }
+}
+
+/// A command the client's role does not allow
+#[derive(Debug)]
+pub struct PermissionDenied {
+    role: Role,
+    command: ServerCommand,
+}
+
+impl fmt::Display for PermissionDenied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "permission denied: {} is not permitted for a {}", self.command, self.role)
+    }
+}
+
+impl std::error::Error for PermissionDenied {}
+
+impl PermissionDenied {
+    /// Fails unless `role` may run `command`
+    pub fn check(role: Role, command: &ServerCommand) -> Result<()> {
+        if role.permits(command) {
+            return Ok(());
+        }
+        let command = command.clone();
+        Err(PermissionDenied { role, command }.into())
+    }
+}
+
 /// Issues commands from the network to the Navigator and Window.
 pub struct Controller {
     /// Navigator holds a cursor for moving through list of Image files
     nav: Navigator,
     /// Window holds Sdl window and update functions
     win: Window,
-    /// Channel to transmit response back to the network service
-    tx_res: Sender<Bytes>,
     /// Channel to recieve request from network service
-    rx_req: Receiver<Bytes>,
+    rx_req: Receiver<Envelope>,
     /// A mutext to exit tasks gracefully
     exiting: Arc<Mutex<bool>>,
     /// If enabled, display will automatically update periodically
     pageant: PageantMode,
+    /// Display changes broadcast to every connection
+    events: broadcast::Sender<ServerEvent>,
+    /// PIN to show while a client pairs, if clients have to
+    pairing: Option<Pairing>,
 }
 
 impl Controller {
     pub fn new(
         path: &Path,
-        rx_req: Receiver<Bytes>,
-        tx_res: Sender<Bytes>,
+        rx_req: Receiver<Envelope>,
+        events: broadcast::Sender<ServerEvent>,
+        pairing: Option<Pairing>,
         exiting: Arc<Mutex<bool>>,
     ) -> Result<Self> {
         let nav = Navigator::new(path)?;
@@ -47,62 +110,79 @@ impl Controller {
         let c = Controller {
             nav,
             win,
-            tx_res,
             rx_req,
             exiting,
             pageant,
+            events,
+            pairing,
         };
         Ok(c)
     }
     pub fn next(&mut self) -> Result<()> {
-        let _result = self.handle_command(ServerCommand::Next);
+        let _result = self.handle_command(Role::Controller, ServerCommand::Next, None);
         Ok(())
     }
     pub fn _prev(&mut self) -> Result<()> {
-        let _result = self.handle_command(ServerCommand::Prev);
+        let _result = self.handle_command(Role::Controller, ServerCommand::Prev, None);
         Ok(())
     }
-    /// Parse request and send response back to que Quic Service
+    /// Run queued requests and send each response back to the stream
+    /// that issued it.
     pub fn handle_request(&mut self) -> Result<()> {
-        while let Ok(bytes) = self.rx_req.try_recv() {
-            let request = Request::from_bytes(bytes)?;
+        while let Ok(Envelope { request, role, reply, data }) = self.rx_req.try_recv() {
             debug!("request: {:?}", request);
 
-            let p = self.nav.image_path();
-            let resp = match self.handle_command(request.command()) {
-                Ok(maybe_data) => {
-                    let message = "Success";
-                    Response::new(Some(p), maybe_data, message)
-                }
-                Err(e) => {
-                    let message = format! {"Error: {}", e};
-                    Response::new(Some(p), None, &message)
-                }
+            let command = request.command().clone();
+            let started = Instant::now();
+            let result = self.handle_command(role, command.clone(), data);
+            metrics().command(&command, started.elapsed());
+            let (status, message) = match result {
+                Ok(()) => (Status::Success, "Success".to_string()),
+                Err(e) if e.is::<PermissionDenied>() => (Status::PermissionDenied, e.to_string()),
+                Err(e) => (Status::Failed, format! {"Error: {}", e}),
             };
-            self.tx_res.send(resp.to_bytes()?.into())?;
+            let path = Some(self.nav.image_path());
+            let resp = Response::new(request.id(), status, path, &message)
+                .with_position(self.position());
+            // the stream may have closed while the command ran
+            if reply.send(resp).is_err() {
+                debug!("dropping response to closed stream: {}", request.id());
+            }
         }
         Ok(())
     }
-    /// Call navigator and window commands according to network request.
-    pub fn handle_command(&mut self, command: ServerCommand) -> Result<Option<Vec<u8>>> {
+    /// Call navigator and window commands according to network request,
+    /// if `role` allows the command. `data` is the image of a Cast.
+    pub fn handle_command(
+        &mut self,
+        role: Role,
+        command: ServerCommand,
+        data: Option<Vec<u8>>,
+    ) -> Result<()> {
+        PermissionDenied::check(role, &command)?;
         match command {
-            ServerCommand::Fetch => {
-                let data = self.nav.image_data()?;
-                Ok(Some(data))
-            }
+            // the connection streams the file named in the response,
+            // away from the display loop
+            ServerCommand::Fetch { .. } => Ok(()),
+            ServerCommand::Status => Ok(()),
             ServerCommand::Fullscreen => {
                 self.win.fullscreen_toggle(&self.nav.image)?;
-                Ok(None)
+                self.notify(ServerEvent::Fullscreen(self.win.is_fullscreen()));
+                Ok(())
             }
             ServerCommand::Rotate => {
                 self.win.rotate(1.0, &self.nav.image)?;
-                Ok(None)
+                self.notify(ServerEvent::Rotated(self.win.rotation()));
+                Ok(())
             }
             ServerCommand::Pageant => {
                 self.pageant.toggle();
-                Ok(None)
+                self.notify(ServerEvent::Pageant(self.pageant.is_enabled()));
+                Ok(())
             }
             ServerCommand::Next => {
+                self.leave_cast();
+                let count = self.nav.count();
                 // loop until we get a supported image. Test if image
                 // is supported by loading it in the window.
                 let image = loop {
@@ -113,14 +193,69 @@ impl Controller {
                     if let Some(_t) = self.win.try_load(image) {
                         break image;
                     } else {
+                        metrics().skipped_image();
                         self.nav.delete();
                     }
                 };
 
                 self.win.update(image)?;
-                Ok(None)
+                self.notify_image_changed(count);
+                Ok(())
+            }
+            // the connection stored the file before passing this on
+            ServerCommand::Upload { name, show, .. } => {
+                let path = self.nav.dir().join(&name);
+                if self.win.try_load(&path).is_none() {
+                    std::fs::remove_file(&path)?;
+                    return Err(anyhow!("{} is not a supported image", name));
+                }
+                let index = self.nav.insert(path);
+                self.notify(ServerEvent::ListChanged(self.nav.count()));
+                if show {
+                    self.leave_cast();
+                    let image = self
+                        .nav
+                        .show(index)
+                        .ok_or(anyhow!("Controller: image get error"))?;
+                    self.win.update(image)?;
+                    self.notify(ServerEvent::ImageChanged(self.nav.image_path(), self.position()));
+                }
+                Ok(())
+            }
+            ServerCommand::Cast { .. } => {
+                let data = data.ok_or(anyhow!("Cast Error: no image sent"))?;
+                self.win.cast(data, &self.nav.image)?;
+                self.notify(ServerEvent::Cast(true));
+                Ok(())
+            }
+            ServerCommand::Goto { index } => {
+                let count = self.nav.count();
+                let image = usize::try_from(index)
+                    .ok()
+                    .and_then(|index| self.nav.get(index))
+                    .ok_or(anyhow!("no image {}, there are {}", index.saturating_add(1), count))?;
+                // unlike next and prev, stay put on an unsupported image
+                if self.win.try_load(image).is_none() {
+                    return Err(anyhow!("{} is not a supported image", image.display()));
+                }
+                self.leave_cast();
+                let image = self
+                    .nav
+                    .show(index as usize)
+                    .ok_or(anyhow!("Controller: image get error"))?;
+                self.win.update(image)?;
+                self.notify(ServerEvent::ImageChanged(self.nav.image_path(), self.position()));
+                Ok(())
+            }
+            ServerCommand::EndCast => {
+                if self.leave_cast() {
+                    self.win.update_canvas(&self.nav.image)?;
+                }
+                Ok(())
             }
             ServerCommand::Prev => {
+                self.leave_cast();
+                let count = self.nav.count();
                 // loop until we get a supported image. Test if image
                 // is supported by loading it in the window.
                 let image = loop {
@@ -131,14 +266,46 @@ impl Controller {
                     if let Some(_t) = self.win.try_load(image) {
                         break image;
                     } else {
+                        metrics().skipped_image();
                         self.nav.delete();
                     }
                 };
                 self.win.update(image)?;
-                Ok(None)
+                self.notify_image_changed(count);
+                Ok(())
             }
         }
     }
+    /// Drop the cast image, if there is one, so the next redraw shows
+    /// the current image again. Returns whether there was one.
+    fn leave_cast(&mut self) -> bool {
+        let left = self.win.end_cast();
+        if left {
+            self.notify(ServerEvent::Cast(false));
+        }
+        left
+    }
+    /// Where the image on display is in the list
+    fn position(&self) -> Option<Position> {
+        let index = self.nav.index()?;
+        Some(Position {
+            index: index as u64,
+            count: self.nav.count() as u64,
+        })
+    }
+    /// Publish a display change to connected clients
+    fn notify(&self, event: ServerEvent) {
+        // an error only means nobody is subscribed right now
+        let _ = self.events.send(event);
+    }
+    /// Announce the new image, and the new list size if unsupported
+    /// files were dropped on the way to it.
+    fn notify_image_changed(&self, count_before: usize) {
+        if self.nav.count() != count_before {
+            self.notify(ServerEvent::ListChanged(self.nav.count()));
+        }
+        self.notify(ServerEvent::ImageChanged(self.nav.image_path(), self.position()));
+    }
     /// Handle Window events
     pub fn handle_events(&mut self) {
         for event in self.win.poll_events() {
@@ -152,11 +319,26 @@ impl Controller {
             };
         }
     }
+    /// Put the pairing PIN on screen while one is live, and take it
+    /// off once it is used or expires.
+    pub fn pairing(&mut self) {
+        let Some(pairing) = &self.pairing else {
+            return;
+        };
+        let pin = pairing.pin();
+        if pin.as_deref() != self.win.pin() {
+            if let Err(e) = self.win.show_pin(pin, &self.nav.image) {
+                debug!("pairing PIN not shown: {}", e);
+            }
+        }
+    }
     /// Update image if we in pageant mode and timeout has elapsed
     pub fn pageant(&mut self) {
-        if self.pageant.should_update() {
+        // a cast image stays up until it is taken down
+        if self.pageant.should_update() && !self.win.is_casting() {
             self.pageant.set_instant();
             let _ = self.next();
+            metrics().pageant_advanced();
         };
     }
 }
@@ -24,7 +24,7 @@ impl PathCursor {
     /// get current index then advance
     pub fn next(&mut self) -> Option<&PathBuf> {
         // if not None use the index, else set it to 0
-        let index = self.index.map_or_else(|| 0, |i| i);
+        let index = self.index.unwrap_or(0);
 
         // if get returns an item, it must be a valid index. Otherwise cycle
         // get the first image and set the index for the next call to 1
@@ -40,7 +40,7 @@ impl PathCursor {
     /// get previous
     pub fn prev(&mut self) -> Option<&PathBuf> {
         // if not None use the index, else set it to 0
-        let mut index = self.index.map_or_else(|| 0, |i| i);
+        let mut index = self.index.unwrap_or(0);
         let path = if index == 0 {
             index = self.len - 1;
             self.paths.last()
@@ -51,6 +51,10 @@ impl PathCursor {
         self.index = Some(index);
         path
     }
+    /// number of paths in the cursor
+    pub fn count(&self) -> usize {
+        self.len
+    }
     /// remove
     pub fn remove(&mut self) -> Option<PathBuf> {
         if let Some(index) = self.index {
@@ -62,6 +66,34 @@ impl PathCursor {
         }
     }
 
+    /// Add `path` at its place in file name order, keeping the cursor
+    /// on the same path. Returns where it went.
+    pub fn insert(&mut self, path: PathBuf) -> usize {
+        let at = self
+            .paths
+            .partition_point(|p| p.file_name() < path.file_name());
+        self.paths.insert(at, path);
+        self.len += 1;
+        if let Some(index) = self.index.filter(|index| at < *index) {
+            self.index = Some(index + 1);
+        }
+        at
+    }
+    /// The path at `index`, without moving
+    pub fn get(&self, index: usize) -> Option<&PathBuf> {
+        self.paths.get(index)
+    }
+    /// Where `path` is, without moving
+    pub fn position(&self, path: &Path) -> Option<usize> {
+        self.paths.iter().position(|p| p == path)
+    }
+    /// Move to the path at `index`, as if next() had returned it
+    pub fn seek(&mut self, index: usize) -> Option<&PathBuf> {
+        let path = self.paths.get(index)?;
+        self.index = Some(index + 1);
+        Some(path)
+    }
+
     /// Import all the files under given dir path, performing some sanity checks.
     pub fn import_files(path: &Path) -> Result<Self> {
         let read_dir = std::fs::read_dir(path).map_err(|e| anyhow!("Get Path Error {}", e))?;
@@ -89,7 +121,7 @@ mod tests {
     use super::*;
     use std::path::Path;
     fn get_paths() -> Vec<PathBuf> {
-        let v = vec![
+        let v = [
             Path::new("./foo/bar.txt"),
             Path::new("./bim/bam.txt"),
             Path::new("./bar/foo.txt"),
@@ -118,4 +150,19 @@ mod tests {
         assert_eq!(v.prev(), Some(&Path::new("./bar/foo.txt").to_path_buf()));
         Ok(())
     }
+    #[test]
+    fn test_cursor_insert() -> Result<()> {
+        let paths = ["./a/1.jpg", "./a/3.jpg", "./a/5.jpg"];
+        let mut v = PathCursor::new(paths.iter().map(PathBuf::from).collect());
+        v.next();
+        v.next();
+        assert_eq!(v.insert(PathBuf::from("./a/2.jpg")), 1);
+        assert_eq!(v.insert(PathBuf::from("./a/9.jpg")), 4);
+        assert_eq!(v.count(), 5);
+        // still after 3.jpg
+        assert_eq!(v.next(), Some(&PathBuf::from("./a/5.jpg")));
+        assert_eq!(v.seek(1), Some(&PathBuf::from("./a/2.jpg")));
+        assert_eq!(v.next(), Some(&PathBuf::from("./a/3.jpg")));
+        Ok(())
+    }
 }
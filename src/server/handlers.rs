@@ -1,48 +1,825 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+
 use anyhow::{anyhow, bail, Result};
-use bytes::Bytes;
-use s2n_quic::{stream::BidirectionalStream, Connection};
-use tracing::{debug, error, info};
-use crossbeam_channel::{Sender, Receiver};
+use async_compression::{tokio::write::ZstdEncoder, Level};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{timeout_at, Instant};
+use tracing::{debug, error, info, warn};
+use crossbeam_channel::Sender;
+
+use crate::image::{content_type, is_compressed};
+use crate::model::{
+    read_frame, write_frame, Capabilities, Compression, FileInfo, Hello, HelloReply, Intent, PairReply,
+    PairRequest, Request, Response, Role, ServerCommand, ServerEvent, Status, StreamHeader,
+    Wire, PROTOCOL_VERSION, ZSTD_LEVEL,
+};
+use crate::server::controller::{Envelope, PermissionDenied};
+use crate::server::limits::ClientLimits;
+use crate::server::metrics::metrics;
+use crate::server::pairing::{sanitize_name, Attempt, Pairing, PIN_LIFETIME};
+use crate::server::upload::Uploads;
+use crate::transport::{Connection, Datagrams, Opener, Reader, Stream, Writer};
+
+/// Bytes read from disk at a time when sending a fetched file
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Time a stream beyond the limit has to send the request refused on it
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// spawn tasks to accept connection, connect to stream and pass
-/// input down the wire
-pub async fn handle_connection(tx: Sender<Bytes>, rx: Receiver<Bytes>, mut connection: Connection) -> Result<()> {
+/// input down the wire. `admission` is the client's role, or the reason
+/// it is turned away during the handshake unless it came to pair.
+/// Streams and commands beyond `limits` are refused.
+pub async fn handle_connection(
+    tx: Sender<Envelope>,
+    events: broadcast::Sender<ServerEvent>,
+    mut connection: Connection,
+    admission: Result<Role, String>,
+    pairing: Option<Pairing>,
+    uploads: Uploads,
+    limits: ClientLimits,
+) -> Result<()> {
+    let _active = metrics().connection();
+    let refusal = admission.as_ref().err();
+    let mut handshake_done = false;
+    let mut wire = Wire::default();
     loop {
-        let stream = match connection.accept_bidirectional_stream().await {
+        let mut stream = match connection.acceptor.accept_bidirectional().await {
             Ok(Some(stream)) => stream,
             Ok(None) => {
                 info! {"connection closed"};
                 return Ok(());
             }
-            Err(e) => bail!("{}", e.to_string()),
+            Err(e) => bail!("{}", e),
+        };
+        let Some(permit) = limits.stream() else {
+            warn!("refusing a stream, the connection has too many open");
+            if let Err(e) = refuse_stream(stream, wire).await {
+                debug!("refused stream: {reason}", reason = e.to_string());
+            }
+            continue;
         };
-        let fut = handle_request(tx.clone(), rx.clone(), stream);
+        // the first stream opened on a connection carries the handshake
+        if !handshake_done {
+            let refusal = refusal.map(|r| r.as_str());
+            // intents only move the display, and need datagrams to travel
+            let available = match (&connection.datagrams, &admission) {
+                (Some(_), Ok(Role::Controller)) => Capabilities::SUPPORTED,
+                _ => Capabilities::SUPPORTED.without(Capabilities::DATAGRAMS),
+            };
+            let (version, capabilities) = handshake(&mut stream, refusal, available, pairing.is_some()).await?;
+            handshake_done = true;
+            wire = Wire::negotiated(version, capabilities);
+            if let (true, Some(pairing)) = (capabilities.contains(Capabilities::PAIRING), &pairing) {
+                return pair(&mut stream, pairing, wire, &limits).await;
+            }
+            if capabilities.contains(Capabilities::EVENTS) {
+                let send = connection.opener.open_send().await?;
+                let fut = push_events(events.subscribe(), send, wire);
+                tokio::spawn(async move {
+                    if let Err(e) = fut.await {
+                        debug!("event stream closed: {reason}", reason = e.to_string());
+                    }
+                });
+            }
+            if let (true, Some(datagrams)) = (
+                capabilities.contains(Capabilities::DATAGRAMS),
+                connection.datagrams.clone(),
+            ) {
+                let role = admission.clone().unwrap_or(Role::Viewer);
+                let fut = receive_intents(tx.clone(), datagrams, role, limits.clone(), wire);
+                tokio::spawn(async move {
+                    if let Err(e) = fut.await {
+                        debug!("intents stopped: {reason}", reason = e.to_string());
+                    }
+                });
+            }
+        }
+        // only an admitted client gets past the handshake
+        let role = admission.clone().unwrap_or(Role::Viewer);
+        let fut = handle_request(
+            tx.clone(),
+            stream,
+            role,
+            connection.opener.clone(),
+            uploads.clone(),
+            limits.clone(),
+            wire,
+        );
         tokio::spawn(async move {
             if let Err(e) = fut.await {
                 error!("failed: {reason}", reason = e.to_string());
             }
+            drop(permit);
         });
     }
 }
-/// Sends request to controler and waits for response. Forwards response to client
-pub async fn handle_request(tx: Sender<Bytes>, rx: Receiver<Bytes>, mut stream: BidirectionalStream) -> Result<()> {
+/// Answer the request on a stream beyond the connection's limit with
+/// `Status::RateLimited` and close it. The accept loop waits on this, so
+/// a client opening streams faster than it closes them is held up.
+async fn refuse_stream(mut stream: Stream, wire: Wire) -> Result<()> {
+    let id = match tokio::time::timeout(REFUSAL_TIMEOUT, read_frame(&mut stream)).await {
+        Ok(Ok(Some(bytes))) => Request::from_bytes(bytes, wire).map_or(0, |request| request.id()),
+        _ => 0,
+    };
+    let message = "too many streams open on this connection";
+    let response = Response::new(id, Status::RateLimited, None, message);
+    write_frame(&mut stream, &response.to_bytes(wire)?).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+/// Read the client's Hello and answer it. Returns the agreed version
+/// and capabilities, out of those `available` to this client, or
+/// errors if the client can't be served after telling it why. A client
+/// asking to pair is let in with `can_pair`, even if it would otherwise
+/// be refused.
+pub async fn handshake(
+    stream: &mut Stream,
+    refusal: Option<&str>,
+    available: Capabilities,
+    can_pair: bool,
+) -> Result<(u16, Capabilities)> {
+    let bytes = read_frame(stream)
+        .await?
+        .ok_or(anyhow!("stream closed before handshake"))?;
+    let reject = |reason: String| HelloReply::Reject {
+        version: PROTOCOL_VERSION,
+        reason,
+    };
+    let reply = match Hello::from_bytes(bytes) {
+        Ok(hello) => match (hello.reply(), refusal) {
+            (HelloReply::Accept { version, capabilities }, _)
+                if hello.capabilities().contains(Capabilities::PAIRING) =>
+            {
+                if can_pair {
+                    // the exchange goes in the encoding asked for
+                    let capabilities = capabilities
+                        .intersection(Capabilities::ENCODINGS)
+                        .with(Capabilities::PAIRING);
+                    HelloReply::Accept { version, capabilities }
+                } else {
+                    reject("this server lets every client in, no pairing needed".to_string())
+                }
+            }
+            (HelloReply::Accept { .. }, Some(reason)) => reject(reason.to_string()),
+            (HelloReply::Accept { version, capabilities }, None) => HelloReply::Accept {
+                version,
+                capabilities: capabilities.intersection(available),
+            },
+            (reply, _) => reply,
+        },
+        Err(e) => reject(format!("malformed handshake: {}", e)),
+    };
+    write_frame(stream, &reply.to_bytes()?).await?;
+    match reply {
+        HelloReply::Accept { version, capabilities } => {
+            debug!("handshake: version {} capabilities {:?}", version, capabilities);
+            Ok((version, capabilities))
+        }
+        HelloReply::Reject { reason, .. } => {
+            // make sure the reason arrives before the connection goes
+            stream.shutdown().await.ok();
+            warn!("handshake rejected: {}", reason);
+            bail!("handshake rejected: {}", reason)
+        }
+    }
+}
+/// Show a PIN on the display and issue a credential to the client once
+/// it sends the PIN back. Asking to pair counts against the client's
+/// `limits`.
+pub async fn pair(stream: &mut Stream, pairing: &Pairing, wire: Wire, limits: &ClientLimits) -> Result<()> {
+    let started = limits
+        .check_pairing()
+        .map_err(anyhow::Error::from)
+        .and_then(|_| pairing.start(limits.client()));
+    let ticket = match started {
+        Ok(ticket) => ticket,
+        Err(e) => {
+            write_frame(stream, &PairReply::Refused(e.to_string()).to_bytes(wire)?).await?;
+            return Ok(());
+        }
+    };
+    let result = exchange_pin(stream, pairing, ticket, wire).await;
+    pairing.cancel(ticket);
+    result
+}
+async fn exchange_pin(stream: &mut Stream, pairing: &Pairing, ticket: u64, wire: Wire) -> Result<()> {
+    write_frame(stream, &PairReply::PinShown(PIN_LIFETIME.as_secs()).to_bytes(wire)?).await?;
+    let deadline = Instant::now() + PIN_LIFETIME;
+    loop {
+        let bytes = match timeout_at(deadline, read_frame(stream)).await {
+            Ok(bytes) => bytes?.ok_or(anyhow!("client left while pairing"))?,
+            Err(_) => {
+                let reply = PairReply::Refused("the PIN has expired".to_string());
+                write_frame(stream, &reply.to_bytes(wire)?).await?;
+                return Ok(());
+            }
+        };
+        let request = PairRequest::from_bytes(bytes, wire)?;
+        let name = sanitize_name(&request.name);
+        let reply = match pairing.complete(ticket, &request.pin, &name, request.role) {
+            Ok((identity, role)) => {
+                info!("paired {} as {} ({})", name, role, identity.fingerprint()?);
+                PairReply::Paired {
+                    cert: identity.cert,
+                    key: identity.key,
+                    role,
+                }
+            }
+            Err(Attempt::Retry(reason)) => PairReply::Retry(reason),
+            Err(Attempt::Over(reason)) => {
+                warn!("pairing {} failed: {}", name, reason);
+                PairReply::Refused(reason)
+            }
+        };
+        write_frame(stream, &reply.to_bytes(wire)?).await?;
+        if !matches!(reply, PairReply::Retry(_)) {
+            stream.shutdown().await.ok();
+            return Ok(());
+        }
+    }
+}
+/// Copy display events to the client until either side goes away.
+pub async fn push_events(
+    mut events: broadcast::Receiver<ServerEvent>,
+    mut stream: Writer,
+    wire: Wire,
+) -> Result<()> {
+    write_frame(&mut stream, &StreamHeader::Events.to_bytes(wire)?).await?;
     loop {
-        match stream.receive().await {
-            Ok(Some(request)) => {
-                tx.send(request)
-                    .map_err(|e| anyhow!("control reciever closed: {}", e))?;
-
-		// block to wait for response
-		// send it to the client
-		if let Ok(response) = rx.recv() {
-                    stream
-			.send(response)
-			.await
-			.map_err(|e| anyhow!("channel empty: {}", e))?;
-		}
-            }
-            Ok(None) => debug!("stream finished"),
-            Err(e) => bail!("{}", e.to_string()),
+        match events.recv().await {
+            Ok(event) => write_frame(&mut stream, &event.to_bytes(wire)?).await?,
+            // a slow client misses some events but keeps the stream
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("client lagging, dropped {} events", missed)
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+/// Show the image each `Intent` from the client asks for. Intents
+/// arriving while the controller is busy pile up, only the newest of
+/// them is acted on once it is free again. Like requests they are held
+/// to the client's `role` and `limits`, but having no reply those that
+/// fail either are dropped.
+pub async fn receive_intents(
+    tx: Sender<Envelope>,
+    datagrams: Arc<dyn Datagrams>,
+    role: Role,
+    limits: ClientLimits,
+    wire: Wire,
+) -> Result<()> {
+    let mut latest = None;
+    while let Some(data) = datagrams.receive().await {
+        let mut newest: Option<Intent> = None;
+        for data in std::iter::once(data).chain(std::iter::from_fn(|| datagrams.try_receive())) {
+            match Intent::from_bytes(data, wire) {
+                Ok(intent) if newest.is_none_or(|newest| intent.seq > newest.seq) => {
+                    newest = Some(intent)
+                }
+                Ok(_) => {}
+                Err(e) => debug!("bad intent: {}", e),
+            }
+        }
+        // a late datagram may have been overtaken by one already shown
+        let Some(intent) = newest.filter(|intent| latest.is_none_or(|seq| intent.seq > seq)) else {
+            continue;
+        };
+        latest = Some(intent.seq);
+        let request = Request::with_command(intent.seq, ServerCommand::Goto { index: intent.index });
+        if let Err(e) = PermissionDenied::check(role, request.command()) {
+            debug!("intent {} dropped: {}", intent.seq, e);
+            continue;
+        }
+        if let Err(e) = limits.check(request.command()) {
+            debug!("intent {} dropped: {}", intent.seq, e);
+            continue;
+        }
+        let response = Envelope::send(&tx, request, role).await?;
+        if response.status() != Status::Success {
+            debug!("intent {} failed: {}", intent.seq, response.message());
+        }
+    }
+    Ok(())
+}
+/// Forwards requests to the controller along with the client's role
+/// and a reply channel owned by this stream, and writes responses back
+/// as they arrive. Files the controller agrees to let the client fetch
+/// are sent on streams opened through `opener`, and files the client
+/// uploads are stored in `uploads` before the controller hears of them.
+pub async fn handle_request(
+    tx: Sender<Envelope>,
+    stream: Stream,
+    role: Role,
+    opener: Arc<dyn Opener>,
+    uploads: Uploads,
+    limits: ClientLimits,
+    wire: Wire,
+) -> Result<()> {
+    let (mut receive, mut send) = stream.split();
+    let (reply, mut responses) = mpsc::unbounded_channel::<Response>();
+    // ranges of Fetch requests still waiting for their response
+    let fetches = Arc::new(Mutex::new(HashMap::new()));
+
+    // send responses to the client in the order the controller answers
+    let pending = fetches.clone();
+    let writer = tokio::spawn(async move {
+        while let Some(response) = responses.recv().await {
+            let range = pending.lock().unwrap().remove(&response.id());
+            let (response, download) = match range {
+                Some((offset, length)) => open_fetch(response, offset, length).await,
+                None => (response, None),
+            };
+            write_frame(&mut send, &response.to_bytes(wire)?)
+                .await
+                .map_err(|e| anyhow!("stream send error: {}", e))?;
+            if let Some(download) = download {
+                let fut = download.send(opener.clone(), response.id(), wire);
+                tokio::spawn(async move {
+                    if let Err(e) = fut.await {
+                        warn!("fetch failed: {reason}", reason = e.to_string());
+                    }
+                });
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    });
+
+    while let Some(bytes) = read_frame(&mut receive).await? {
+        let request = Request::from_bytes(bytes, wire)?;
+        if let Err(limited) = limits.check(request.command()) {
+            debug!("{} from a client over its rate: {}", request.command(), limited);
+            let response = Response::new(request.id(), Status::RateLimited, None, &limited.to_string());
+            reply.send(response).ok();
+            // an upload or cast is followed by bytes that can't be framed
+            if matches!(request.command(), ServerCommand::Upload { .. } | ServerCommand::Cast { .. }) {
+                receive.stop_sending();
+                break;
+            }
+            continue;
+        }
+        if let ServerCommand::Fetch { offset, length } = request.command() {
+            fetches.lock().unwrap().insert(request.id(), (*offset, *length));
+        }
+        let data = match receive_data(&mut receive, request.command(), role, &uploads).await {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("{} refused: {}", request.command(), e);
+                let (status, message) = match e.is::<PermissionDenied>() {
+                    true => (Status::PermissionDenied, e.to_string()),
+                    false => (Status::Failed, format!("Error: {}", e)),
+                };
+                reply.send(Response::new(request.id(), status, None, &message)).ok();
+                // the rest of the stream can't be framed, so it ends here
+                receive.stop_sending();
+                break;
+            }
+        };
+        let envelope = Envelope {
+            request,
+            role,
+            reply: reply.clone(),
+            data,
+        };
+        tx.send(envelope)
+            .map_err(|e| anyhow!("control reciever closed: {}", e))?;
+    }
+    debug!("stream finished");
+
+    // let the writer drain once outstanding requests are answered
+    drop(reply);
+    writer.await?
+}
+/// Take in the bytes following an Upload or Cast request, if the client
+/// may send them. An upload is stored, a cast image is returned to be
+/// passed on to the display.
+async fn receive_data(
+    receive: &mut Reader,
+    command: &ServerCommand,
+    role: Role,
+    uploads: &Uploads,
+) -> Result<Option<Vec<u8>>> {
+    match command {
+        ServerCommand::Upload { name, size, .. } => {
+            PermissionDenied::check(role, command)?;
+            uploads.receive(receive, name, *size).await?;
+            Ok(None)
+        }
+        ServerCommand::Cast { size } => {
+            PermissionDenied::check(role, command)?;
+            uploads.read(receive, *size).await.map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+/// Open the file a successful Fetch names. If it can't be sent the
+/// client is told why instead.
+async fn open_fetch(
+    response: Response,
+    offset: u64,
+    length: Option<u64>,
+) -> (Response, Option<Download>) {
+    let path = match (response.status(), response.path()) {
+        (Status::Success, Some(path)) => path.to_path_buf(),
+        _ => return (response, None),
+    };
+    match Download::open(&path, offset, length).await {
+        Ok(download) => (response, Some(download)),
+        Err(e) => {
+            let message = format!("Error: {}", e);
+            let response = Response::new(response.id(), Status::Failed, Some(path), &message);
+            (response, None)
+        }
+    }
+}
+
+/// A range of a file to send in answer to a Fetch
+pub struct Download {
+    file: File,
+    path: PathBuf,
+    stamp: Stamp,
+    size: u64,
+    offset: u64,
+    length: u64,
+}
+
+impl Download {
+    /// Open `path` to send `length` bytes, or the rest of it, from
+    /// `offset`.
+    pub async fn open(path: &Path, offset: u64, length: Option<u64>) -> Result<Download> {
+        let file = File::open(path)
+            .await
+            .map_err(|e| anyhow!("Fetch Error: {}: {}", path.display(), e))?;
+        let metadata = file.metadata().await?;
+        let size = metadata.len();
+        if offset > size {
+            bail!("Fetch Error: offset {} is past the end of the file ({} bytes)", offset, size);
+        }
+        let length = length.map_or(size - offset, |l| l.min(size - offset));
+        Ok(Download {
+            file,
+            path: path.to_path_buf(),
+            stamp: Stamp::of(&metadata),
+            size,
+            offset,
+            length,
+        })
+    }
+    /// Send the range to the client on a stream of its own, so a large
+    /// image doesn't hold up responses or events. The stream carries a
+    /// `StreamHeader::Download` frame followed by the range's bytes, or
+    /// a `StreamHeader::ZstdDownload` one and the bytes zstd compressed
+    /// if `wire` is, unless the image is already.
+    pub async fn send(mut self, opener: Arc<dyn Opener>, id: u64, wire: Wire) -> Result<()> {
+        let name = self
+            .path
+            .file_name()
+            .ok_or(anyhow!("Fetch Error: {} is not a file", self.path.display()))?
+            .to_string_lossy()
+            .into_owned();
+        // the client checks the file it puts together against this
+        let hash = hash_file(&self.path, self.stamp, &self.file).await?;
+        debug!("sending {} bytes of {} from {} for request {}", self.length, name, self.offset, id);
+
+        let compressed = wire.compression == Compression::Zstd && !is_compressed(content_type(&self.path));
+        let mut stream = opener.open_send().await?;
+        let info = FileInfo {
+            id,
+            name,
+            size: self.size,
+            hash,
+            offset: self.offset,
+            length: self.length,
+        };
+        let header = match compressed {
+            true => StreamHeader::ZstdDownload(info),
+            false => StreamHeader::Download(info),
+        };
+        write_frame(&mut stream, &header.to_bytes(wire)?).await?;
+        self.file.seek(SeekFrom::Start(self.offset)).await?;
+        // never send more than the header promised, should the file grow
+        let mut reader = BufReader::with_capacity(CHUNK_SIZE, self.file.take(self.length));
+        let mut stream: Writer = match compressed {
+            true => Box::new(ZstdEncoder::with_quality(stream, Level::Precise(ZSTD_LEVEL))),
+            false => stream,
+        };
+        let sent = tokio::io::copy_buf(&mut reader, &mut stream).await?;
+        metrics().fetched(sent);
+        // finishes the zstd stream, if any
+        stream.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// Tells one version of a file from another. Writing over a file or
+/// replacing it changes its change time or inode, whatever it does to
+/// the modification time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    device: u64,
+    inode: u64,
+    changed: (i64, i64),
+    modified: (i64, i64),
+    size: u64,
+}
+
+impl Stamp {
+    fn of(metadata: &std::fs::Metadata) -> Stamp {
+        Stamp {
+            device: metadata.dev(),
+            inode: metadata.ino(),
+            changed: (metadata.ctime(), metadata.ctime_nsec()),
+            modified: (metadata.mtime(), metadata.mtime_nsec()),
+            size: metadata.len(),
+        }
+    }
+}
+
+/// Most hashes kept, the least recently used is forgotten beyond that
+const MAX_HASHES: usize = 256;
+
+/// Hash of a file, the version it was taken of and when it was last
+/// asked for
+struct Hashed {
+    stamp: Stamp,
+    hash: [u8; 32],
+    used: Instant,
+}
+
+/// Hashes of the files fetched lately, by path
+static HASHES: LazyLock<Mutex<HashMap<PathBuf, Hashed>>> = LazyLock::new(Default::default);
+
+/// BLAKE3 hash of `file`, opened from `path` when it was at `stamp`.
+/// Read away from the runtime the first time, so that resuming a large
+/// fetch doesn't read the whole file again.
+async fn hash_file(path: &Path, stamp: Stamp, file: &File) -> Result<[u8; 32]> {
+    if let Some(hashed) = HASHES
+        .lock()
+        .unwrap()
+        .get_mut(path)
+        .filter(|hashed| hashed.stamp == stamp)
+    {
+        hashed.used = Instant::now();
+        return Ok(hashed.hash);
+    }
+    let mut reader = file.try_clone().await?.into_std().await;
+    let hash = tokio::task::spawn_blocking(move || {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(&mut reader)?;
+        Ok::<_, anyhow::Error>(*hasher.finalize().as_bytes())
+    })
+    .await??;
+    let mut hashes = HASHES.lock().unwrap();
+    if hashes.len() >= MAX_HASHES && !hashes.contains_key(path) {
+        let oldest = hashes.iter().min_by_key(|(_, hashed)| hashed.used).map(|(p, _)| p.clone());
+        if let Some(oldest) = oldest {
+            hashes.remove(&oldest);
+        }
+    }
+    let used = Instant::now();
+    hashes.insert(path.to_path_buf(), Hashed { stamp, hash, used });
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    use bytes::Bytes;
+    use s2n_quic::{client::Connect, provider::tls::rustls::Client as TlsClient, Client};
+
+    use crate::model::Position;
+    use crate::server::limits::{Limiter, Limits};
+    use crate::server::quic_service::QuicService;
+    use crate::tls::{ca_verifier, client_config, Identity};
+    use crate::transport::mux::{self, Side};
+    use crate::transport::{datagram_endpoint, Peer, ServerTransport};
+
+    /// Time the stand-in controller takes to show an image
+    const LOAD: Duration = Duration::from_millis(10);
+    /// Arrow keys pressed in a row
+    const STEPS: u64 = 20;
+    const IMAGES: u64 = 100;
+
+    /// Answer requests like the controller would, taking `LOAD` for
+    /// each image shown and reporting its index on `shown`.
+    fn controller(rx: crossbeam_channel::Receiver<Envelope>, shown: mpsc::UnboundedSender<u64>) {
+        std::thread::spawn(move || {
+            let mut index = 0;
+            while let Ok(Envelope { request, reply, .. }) = rx.recv() {
+                match request.command() {
+                    ServerCommand::Next => index = (index + 1) % IMAGES,
+                    ServerCommand::Goto { index: to } => index = *to,
+                    _ => {}
+                }
+                std::thread::sleep(LOAD);
+                shown.send(index).ok();
+                let position = Some(Position { index, count: IMAGES });
+                let path = Some(PathBuf::from(format!("/photos/{}.jpg", index)));
+                let response = Response::new(request.id(), Status::Success, path, "Success");
+                reply.send(response.with_position(position)).ok();
+            }
+        });
+    }
+
+    /// A connection from a client to `handle_connection` with the
+    /// stand-in controller behind it
+    struct Harness {
+        connection: Connection,
+        shown: mpsc::UnboundedReceiver<u64>,
+        _client: Client,
+        _dir: tempfile::TempDir,
+    }
+
+    async fn connect(limits: Limits) -> Result<Harness> {
+        let identity = Identity::generate_for(vec!["localhost".to_string()])?;
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")?.local_addr()?.port();
+        let mut server = QuicService::new(&format!("127.0.0.1:{}", port), &identity, false, limits.max_streams)?;
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let (shown_tx, shown) = mpsc::unbounded_channel();
+        controller(rx, shown_tx);
+        let dir = tempfile::tempdir()?;
+        let uploads = Uploads::new(dir.path(), 1024);
+        let (events, _) = broadcast::channel(16);
+        tokio::spawn(async move {
+            let (connection, peer) = server.accept().await.expect("client");
+            let limits = Limiter::new(limits).client(&peer);
+            handle_connection(tx, events, connection, Ok(Role::Controller), None, uploads, limits).await
+        });
+
+        let tls = TlsClient::from(client_config(ca_verifier(&identity.cert)?, None)?);
+        let client = Client::builder()
+            .with_tls(tls)?
+            .with_datagram(datagram_endpoint()?)?
+            .with_io("0.0.0.0:0")?
+            .start()?;
+        let remote: SocketAddr = format!("127.0.0.1:{}", port).parse()?;
+        let connection = client.connect(Connect::new(remote).with_server_name("localhost")).await?;
+        let connection = Connection::quic(connection);
+        Ok(Harness { connection, shown, _client: client, _dir: dir })
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_intent_latency() -> Result<()> {
+        // holding a key down is what the limit is for, not measured here
+        let Harness { connection, mut shown, .. } = connect(Limits { rate: 0.0, ..Limits::default() }).await?;
+        let mut stream = connection.opener.open_bidirectional().await?;
+        write_frame(&mut stream, &Hello::new(Capabilities::DATAGRAMS).to_bytes()?).await?;
+        let reply = HelloReply::from_bytes(read_frame(&mut stream).await?.expect("reply"))?;
+        assert!(matches!(reply, HelloReply::Accept { capabilities, .. }
+                         if capabilities.contains(Capabilities::DATAGRAMS)));
+
+        // every key waits its turn on the stream, and is shown
+        for id in 0..STEPS {
+            write_frame(&mut stream, &Request::with_command(id, ServerCommand::Next).to_bytes(Wire::default())?).await?;
+        }
+        for _ in 0..STEPS {
+            let response = Response::from_bytes(read_frame(&mut stream).await?.expect("response"), Wire::default())?;
+            assert_eq!(response.status(), Status::Success);
+        }
+        let mut streamed = 0;
+        while shown.try_recv().is_ok() {
+            streamed += 1;
+        }
+        assert_eq!(streamed, STEPS);
+
+        // only the newest intent is shown once the controller is free,
+        // the ones it was too busy for are skipped
+        let datagrams = connection.datagrams.clone().expect("datagrams");
+        let target = 2 * STEPS;
+        for seq in 1..=STEPS {
+            datagrams.send(Intent { seq, index: STEPS + seq }.to_bytes(Wire::default())?.into())?;
+        }
+        let mut loads = 0;
+        loop {
+            let index = tokio::time::timeout(Duration::from_secs(5), shown.recv())
+                .await?
+                .expect("controller");
+            loads += 1;
+            if index == target {
+                break;
+            }
+        }
+        assert!(loads < STEPS, "{} loads for {} intents", loads, STEPS);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_too_many_streams() -> Result<()> {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let client = mux::connection(a, Side::Dialer);
+        let connection = mux::connection(b, Side::Listener);
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let (shown_tx, _shown) = mpsc::unbounded_channel();
+        controller(rx, shown_tx);
+        let dir = tempfile::tempdir()?;
+        let uploads = Uploads::new(dir.path(), 1024);
+        let (events, _) = broadcast::channel(16);
+        let peer = Peer { address: "test".to_string(), fingerprint: None, local: true };
+        let limits = Limiter::new(Limits { max_streams: 2, ..Limits::default() }).client(&peer);
+        tokio::spawn(handle_connection(tx, events, connection, Ok(Role::Controller), None, uploads, limits));
+
+        let mut first = client.opener.open_bidirectional().await?;
+        write_frame(&mut first, &Hello::new(Capabilities::default()).to_bytes()?).await?;
+        read_frame(&mut first).await?.expect("reply");
+        let mut second = client.opener.open_bidirectional().await?;
+        write_frame(&mut second, &Request::with_command(1, ServerCommand::Next).to_bytes(Wire::default())?).await?;
+        read_frame(&mut second).await?.expect("response");
+
+        // both are still open
+        let mut third = client.opener.open_bidirectional().await?;
+        write_frame(&mut third, &Request::with_command(2, ServerCommand::Next).to_bytes(Wire::default())?).await?;
+        let response = Response::from_bytes(read_frame(&mut third).await?.expect("response"), Wire::default())?;
+        assert_eq!(response.id(), 2);
+        assert_eq!(response.status(), Status::RateLimited);
+        assert!(read_frame(&mut third).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hash_cached() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("a.bmp");
+        let hash = |path: PathBuf| async move {
+            let file = File::open(&path).await?;
+            let stamp = Stamp::of(&file.metadata().await?);
+            hash_file(&path, stamp, &file).await
+        };
+        std::fs::write(&path, b"first")?;
+        let modified = std::fs::metadata(&path)?.modified()?;
+        assert_eq!(hash(path.clone()).await?, *blake3::hash(b"first").as_bytes());
+
+        // written over, keeping the size and modification time
+        std::fs::write(&path, b"other")?;
+        std::fs::File::options().write(true).open(&path)?.set_modified(modified)?;
+        assert_eq!(hash(path.clone()).await?, *blake3::hash(b"other").as_bytes());
+
+        // replaced by another file
+        let other = dir.path().join("b.bmp");
+        std::fs::write(&other, b"third")?;
+        std::fs::rename(&other, &path)?;
+        assert_eq!(hash(path.clone()).await?, *blake3::hash(b"third").as_bytes());
+
+        for i in 0..MAX_HASHES {
+            let path = dir.path().join(format!("{}.bmp", i));
+            std::fs::write(&path, i.to_string())?;
+            hash(path).await?;
+        }
+        assert!(HASHES.lock().unwrap().len() <= MAX_HASHES);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_json_client() -> Result<()> {
+        let Harness { connection, mut shown, .. } = connect(Limits::default()).await?;
+        let mut stream = connection.opener.open_bidirectional().await?;
+        let asked = Capabilities::JSON.with(Capabilities::DATAGRAMS);
+        write_frame(&mut stream, &Hello::new(asked).to_bytes()?).await?;
+        let reply = HelloReply::from_bytes(read_frame(&mut stream).await?.expect("reply"))?;
+        assert!(matches!(reply, HelloReply::Accept { capabilities, .. } if capabilities == asked));
+
+        // what a client in another language would write and read
+        let request = br#"{"id":5,"command":{"Goto":{"index":3}}}"#;
+        write_frame(&mut stream, &[&[0x10][..], request].concat()).await?;
+        let bytes = read_frame(&mut stream).await?.expect("response");
+        assert_eq!(bytes[0], 0x10);
+        let response: serde_json::Value = serde_json::from_slice(&bytes[1..])?;
+        assert_eq!(response["id"], 5);
+        assert_eq!(response["status"], "Success");
+        assert_eq!(response["path"], "/photos/3.jpg");
+        assert_eq!(response["position"]["index"], 3);
+        assert_eq!(shown.recv().await, Some(3));
+
+        // intents too
+        let datagrams = connection.datagrams.clone().expect("datagrams");
+        datagrams.send(Bytes::from_static(b"\x10{\"seq\":1,\"index\":7}"))?;
+        let index = tokio::time::timeout(Duration::from_secs(5), shown.recv()).await?;
+        assert_eq!(index, Some(7));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_intents_rate_limited() -> Result<()> {
+        // two display changes in a burst, then one a second
+        let Harness { connection, mut shown, .. } = connect(Limits { rate: 1.0, ..Limits::default() }).await?;
+        let mut stream = connection.opener.open_bidirectional().await?;
+        write_frame(&mut stream, &Hello::new(Capabilities::DATAGRAMS).to_bytes()?).await?;
+        HelloReply::from_bytes(read_frame(&mut stream).await?.expect("reply"))?;
+        let datagrams = connection.datagrams.clone().expect("datagrams");
+        for seq in 1..=2 {
+            datagrams.send(Intent { seq, index: seq }.to_bytes(Wire::default())?.into())?;
+            let index = tokio::time::timeout(Duration::from_secs(5), shown.recv()).await?;
+            assert_eq!(index, Some(seq));
         }
+        datagrams.send(Intent { seq: 3, index: 3 }.to_bytes(Wire::default())?.into())?;
+        assert!(tokio::time::timeout(Duration::from_millis(300), shown.recv()).await.is_err());
+        Ok(())
     }
 }
@@ -0,0 +1,441 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, FromRequestParts, Path as UrlPath, State},
+    http::{header, request::Parts, uri::Authority, StatusCode, Uri},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response as HttpResponse,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use crossbeam_channel::Sender;
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use tokio_util::io::ReaderStream;
+use tracing::{error, info};
+
+use crate::image::content_type;
+use crate::model::{Request, Response, Role, ServerCommand, ServerEvent, Status};
+use crate::server::controller::Envelope;
+use crate::server::limits::{ClientLimits, Limiter, RateLimited};
+use crate::server::tokens::Tokens;
+use crate::transport::Peer;
+
+/// JSON Schema of the answers, served at `/schema`
+const SCHEMA: &str = include_str!("http_schema.json");
+
+/// Remote control page for browsers, served at `/`
+const REMOTE: &str = include_str!("remote.html");
+
+/// Commands that may be posted to `/command/{name}`, for error messages
+const COMMANDS: &str = "next, prev, rotate, fullscreen, pageant, endcast or goto/N";
+
+/// An HTTP listener answering with JSON, for home automation and the
+/// like, and serving a remote control page to browsers. Callers present
+/// a bearer token standing for their role, or without tokens the API
+/// is only open to this machine, and not to web pages from elsewhere
+/// open in its browsers.
+pub struct HttpService {
+    listener: TcpListener,
+    router: Router,
+}
+
+/// What the handlers share
+#[derive(Clone)]
+struct Api {
+    tx_req: Sender<Envelope>,
+    events: broadcast::Sender<ServerEvent>,
+    tokens: Option<Arc<Tokens>>,
+    limiter: Limiter,
+}
+
+/// Who is calling, found from the token it presents and its address
+struct Caller {
+    role: Role,
+    limits: ClientLimits,
+}
+
+/// Answer to a status request or command, described by `SCHEMA`
+#[derive(Debug, Serialize)]
+struct Answer {
+    status: &'static str,
+    // image on display
+    image: Option<String>,
+    // its file name
+    name: Option<String>,
+    message: String,
+}
+
+impl HttpService {
+    /// Listen on `bind`, pass requests on through `tx_req` and stream
+    /// `events` to browsers. Only callers with one of `tokens` are
+    /// served, held to the same `limiter` as clients. Without tokens
+    /// anyone on this machine controls the display, so the API may
+    /// then only listen on loopback and not for a server keeping an
+    /// `allowlist`.
+    pub fn new(
+        bind: &str,
+        tokens: Option<Tokens>,
+        allowlist: bool,
+        limiter: Limiter,
+        tx_req: Sender<Envelope>,
+        events: broadcast::Sender<ServerEvent>,
+    ) -> Result<HttpService> {
+        let listener = std::net::TcpListener::bind(bind)?;
+        let address = listener.local_addr()?;
+        if tokens.is_none() && allowlist {
+            bail!("Http Error: a server with an allowlist needs --http-tokens for the http api");
+        }
+        if tokens.is_none() && !address.ip().is_loopback() {
+            bail!("Http Error: listening on {} needs --http-tokens, or bind 127.0.0.1", address);
+        }
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        info!("http api on {}", address);
+        let tokens = tokens.map(Arc::new);
+        let router = Router::new()
+            .route("/", get(remote))
+            .route("/events", get(event_stream))
+            .route("/status", get(status))
+            .route("/command/{name}", post(command))
+            .route("/command/goto/{position}", post(goto))
+            .route("/image/current", get(current_image))
+            .route("/schema", get(schema))
+            .with_state(Api { tx_req, events, tokens, limiter });
+        Ok(HttpService { listener, router })
+    }
+
+    pub fn listen_task(self) {
+        tokio::spawn(async move {
+            let service = self.router.into_make_service_with_connect_info::<SocketAddr>();
+            if let Err(e) = axum::serve(self.listener, service).await {
+                error!("http api failed: {}", e);
+            }
+        });
+    }
+}
+
+impl Answer {
+    fn new(response: &Response) -> Answer {
+        let path = response.path();
+        Answer {
+            status: status_name(response.status()),
+            image: path.map(|p| p.display().to_string()),
+            name: path
+                .and_then(Path::file_name)
+                .map(|n| n.to_string_lossy().into_owned()),
+            message: response.message().to_string(),
+        }
+    }
+
+    fn failed(message: String) -> Answer {
+        Answer { status: status_name(Status::Failed), image: None, name: None, message }
+    }
+
+    fn limited(limited: RateLimited) -> Answer {
+        let message = limited.to_string();
+        Answer { status: status_name(Status::RateLimited), image: None, name: None, message }
+    }
+}
+
+impl FromRequestParts<Api> for Caller {
+    type Rejection = (StatusCode, Json<Answer>);
+
+    async fn from_request_parts(parts: &mut Parts, api: &Api) -> Result<Caller, Self::Rejection> {
+        let (role, fingerprint) = match &api.tokens {
+            None => {
+                // a page from any site may post here, or read answers
+                // once its name resolves to this machine
+                if !same_machine(parts) {
+                    let answer = Answer::failed("only pages served from this machine may call without a token".to_string());
+                    return Err((StatusCode::FORBIDDEN, Json(answer)));
+                }
+                (Role::Controller, None)
+            }
+            Some(tokens) => {
+                let token = token(parts).unwrap_or_default();
+                let role = tokens.role(token).ok_or_else(|| {
+                    let answer = Answer::failed("a valid token is required".to_string());
+                    (StatusCode::UNAUTHORIZED, Json(answer))
+                })?;
+                // rate limited by token, like clients by certificate
+                (role, Some(Tokens::id(token)))
+            }
+        };
+        let address = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map_or("unknown".to_string(), |ConnectInfo(address)| address.to_string());
+        let peer = Peer { address, fingerprint, local: false };
+        Ok(Caller { role, limits: api.limiter.client(&peer) })
+    }
+}
+
+/// Whether a request names this machine by a loopback address in its
+/// `Host`, and comes from a page that does too if from any
+fn same_machine(parts: &Parts) -> bool {
+    let header = |name| parts.headers.get(name).and_then(|v| v.to_str().ok());
+    let host = header(header::HOST).and_then(|h| h.parse::<Authority>().ok());
+    let origin = match header(header::ORIGIN) {
+        None => true,
+        Some(origin) => origin.parse::<Uri>().ok().and_then(|u| u.host().map(is_loopback)).unwrap_or(false),
+    };
+    host.is_some_and(|h| is_loopback(h.host())) && origin
+}
+
+/// Whether `host` is `localhost` or a loopback address
+fn is_loopback(host: &str) -> bool {
+    let address = host.trim_start_matches('[').trim_end_matches(']');
+    host.eq_ignore_ascii_case("localhost") || address.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Token of a request, from its `Authorization: Bearer` header or
+/// else its `token` query parameter, which browsers can put in links
+fn token(parts: &Parts) -> Option<&str> {
+    let bearer = parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    bearer.or_else(|| parts.uri.query()?.split('&').find_map(|p| p.strip_prefix("token=")))
+}
+
+/// How `status` is spelled in JSON
+fn status_name(status: Status) -> &'static str {
+    match status {
+        Status::Success => "success",
+        Status::Failed => "failed",
+        Status::PermissionDenied => "permission_denied",
+        Status::RateLimited => "rate_limited",
+    }
+}
+
+/// The command `/command/{name}` stands for
+fn parse(name: &str) -> Option<ServerCommand> {
+    match name {
+        "next" => Some(ServerCommand::Next),
+        "prev" => Some(ServerCommand::Prev),
+        "rotate" => Some(ServerCommand::Rotate),
+        "fullscreen" => Some(ServerCommand::Fullscreen),
+        "pageant" => Some(ServerCommand::Pageant),
+        "endcast" => Some(ServerCommand::EndCast),
+        _ => None,
+    }
+}
+
+/// Run `command` on the controller for `caller` and answer with the
+/// outcome
+async fn run(tx_req: &Sender<Envelope>, caller: &Caller, command: ServerCommand) -> (StatusCode, Json<Answer>) {
+    if let Err(limited) = caller.limits.check(&command) {
+        return (StatusCode::TOO_MANY_REQUESTS, Json(Answer::limited(limited)));
+    }
+    let request = Request::with_command(0, command);
+    match Envelope::send(tx_req, request, caller.role).await {
+        Ok(response) => {
+            let code = match response.status() {
+                Status::Success => StatusCode::OK,
+                Status::Failed => StatusCode::INTERNAL_SERVER_ERROR,
+                Status::PermissionDenied => StatusCode::FORBIDDEN,
+                Status::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            };
+            (code, Json(Answer::new(&response)))
+        }
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, Json(Answer::failed(e.to_string()))),
+    }
+}
+
+async fn remote() -> Html<&'static str> {
+    Html(REMOTE)
+}
+
+/// Every change to the display as a server-sent event of its JSON.
+/// Events a slow browser missed are skipped.
+async fn event_stream(State(api): State<Api>, _caller: Caller) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let events = BroadcastStream::new(api.events.subscribe())
+        .filter_map(|event| event.ok())
+        .map(|event| Event::default().json_data(event));
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+async fn status(State(api): State<Api>, caller: Caller) -> impl IntoResponse {
+    run(&api.tx_req, &caller, ServerCommand::Status).await
+}
+
+async fn command(State(api): State<Api>, caller: Caller, UrlPath(name): UrlPath<String>) -> impl IntoResponse {
+    match parse(&name) {
+        Some(command) => run(&api.tx_req, &caller, command).await,
+        None => {
+            let message = format!("unknown command {:?}, expected {}", name, COMMANDS);
+            (StatusCode::NOT_FOUND, Json(Answer::failed(message)))
+        }
+    }
+}
+
+/// Show the image at `position` in the list, counting from 1
+async fn goto(State(api): State<Api>, caller: Caller, UrlPath(position): UrlPath<String>) -> impl IntoResponse {
+    match position.parse::<u64>() {
+        Ok(position) if position > 0 => {
+            run(&api.tx_req, &caller, ServerCommand::Goto { index: position - 1 }).await
+        }
+        _ => {
+            let message = format!("goto takes an image number from 1, not {:?}", position);
+            (StatusCode::BAD_REQUEST, Json(Answer::failed(message)))
+        }
+    }
+}
+
+/// Stream the image on display
+async fn current_image(State(api): State<Api>, caller: Caller) -> HttpResponse {
+    let request = Request::with_command(0, ServerCommand::Status);
+    if let Err(limited) = caller.limits.check(request.command()) {
+        return (StatusCode::TOO_MANY_REQUESTS, Json(Answer::limited(limited))).into_response();
+    }
+    let path = match Envelope::send(&api.tx_req, request, caller.role).await {
+        Ok(response) => response.path().map(Path::to_path_buf),
+        Err(e) => return (StatusCode::SERVICE_UNAVAILABLE, Json(Answer::failed(e.to_string()))).into_response(),
+    };
+    let Some(path) = path else {
+        let answer = Answer::failed("nothing on display".to_string());
+        return (StatusCode::NOT_FOUND, Json(answer)).into_response();
+    };
+    let file = match tokio::fs::File::open(&path).await.map_err(|e| anyhow!("Open Error: {}", e)) {
+        Ok(file) => file,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(Answer::failed(e.to_string()))).into_response(),
+    };
+    // it changes with every command
+    let headers = [
+        (header::CONTENT_TYPE, content_type(&path)),
+        (header::CACHE_CONTROL, "no-store"),
+    ];
+    (headers, Body::from_stream(ReaderStream::new(file))).into_response()
+}
+
+async fn schema() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "application/schema+json")], SCHEMA)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use crate::server::limits::Limits;
+
+    #[test]
+    fn test_schema() {
+        let schema: serde_json::Value = serde_json::from_str(SCHEMA).unwrap();
+        let response = Response::new(1, Status::Success, Some(PathBuf::from("/p/a.jpg")), "Success");
+        let answer = serde_json::to_value(Answer::new(&response)).unwrap();
+        assert_eq!(answer["name"], "a.jpg");
+        // every field is described and required
+        let required: Vec<&str> = schema["required"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_str().unwrap())
+            .collect();
+        let mut fields: Vec<&str> = answer.as_object().unwrap().keys().map(String::as_str).collect();
+        fields.sort();
+        let mut sorted = required.clone();
+        sorted.sort();
+        assert_eq!(fields, sorted);
+        for field in fields {
+            assert!(schema["properties"].get(field).is_some());
+        }
+        let statuses = schema["properties"]["status"]["enum"].as_array().unwrap();
+        for status in [Status::Success, Status::Failed, Status::PermissionDenied, Status::RateLimited] {
+            assert!(statuses.iter().any(|s| s == status_name(status)));
+        }
+    }
+
+    /// Status code of a `method` request for `path` presenting `token`
+    async fn call(address: SocketAddr, method: &str, path: &str, token: Option<&str>) -> Result<u16> {
+        let authorization = token.map_or(String::new(), |t| format!("Authorization: Bearer {}\r\n", t));
+        send(address, method, path, &format!("Host: viewd\r\n{}", authorization)).await
+    }
+
+    /// Status code of a `method` request for `path` with `headers`
+    async fn send(address: SocketAddr, method: &str, path: &str, headers: &str) -> Result<u16> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stream = tokio::net::TcpStream::connect(address).await?;
+        let request = format!("{} {} HTTP/1.1\r\n{}Connection: close\r\n\r\n", method, path, headers);
+        stream.write_all(request.as_bytes()).await?;
+        let mut answer = String::new();
+        stream.read_to_string(&mut answer).await?;
+        let code = answer.split_whitespace().nth(1).ok_or(anyhow!("no status line"))?;
+        Ok(code.parse()?)
+    }
+
+    #[tokio::test]
+    async fn test_tokens_roles_and_limits() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("tokens");
+        std::fs::write(&file, "watcher viewer\ndriver controller\n")?;
+        let (tx_req, rx_req) = crossbeam_channel::unbounded::<Envelope>();
+        // answers like the controller, denying what the role doesn't permit
+        std::thread::spawn(move || {
+            while let Ok(Envelope { request, role, reply, .. }) = rx_req.recv() {
+                let status = match role.permits(request.command()) {
+                    true => Status::Success,
+                    false => Status::PermissionDenied,
+                };
+                reply.send(Response::new(request.id(), status, None, "")).ok();
+            }
+        });
+        let (events, _) = broadcast::channel(4);
+        let limiter = Limiter::new(Limits { rate: 1.0, ..Limits::default() });
+        let http = HttpService::new("0.0.0.0:0", Some(Tokens::load(&file)?), true, limiter, tx_req.clone(), events.clone())?;
+        let address = SocketAddr::from(([127, 0, 0, 1], http.listener.local_addr()?.port()));
+        http.listen_task();
+
+        assert_eq!(call(address, "GET", "/status", None).await?, 401);
+        assert_eq!(call(address, "GET", "/status", Some("nobody")).await?, 401);
+        assert_eq!(call(address, "GET", "/status?token=watcher", None).await?, 200);
+        assert_eq!(call(address, "POST", "/command/next", Some("watcher")).await?, 403);
+        // a burst of two display changes a second, then wait
+        assert_eq!(call(address, "POST", "/command/next", Some("driver")).await?, 200);
+        assert_eq!(call(address, "POST", "/command/prev", Some("driver")).await?, 200);
+        assert_eq!(call(address, "POST", "/command/next", Some("driver")).await?, 429);
+
+        // without tokens only on loopback, and not past an allowlist
+        let limiter = Limiter::new(Limits::default());
+        assert!(HttpService::new("0.0.0.0:0", None, false, limiter.clone(), tx_req.clone(), events.clone()).is_err());
+        assert!(HttpService::new("127.0.0.1:0", None, true, limiter.clone(), tx_req.clone(), events.clone()).is_err());
+        let http = HttpService::new("127.0.0.1:0", None, false, limiter, tx_req, events)?;
+        let address = http.listener.local_addr()?;
+        http.listen_task();
+        let host = format!("Host: {}\r\n", address);
+        assert_eq!(send(address, "GET", "/status", &host).await?, 200);
+        assert_eq!(send(address, "GET", "/status", "Host: localhost\r\n").await?, 200);
+        let page = format!("{}Origin: http://{}\r\n", host, address);
+        assert_eq!(send(address, "POST", "/command/next", &page).await?, 200);
+        assert_eq!(send(address, "POST", "/command/goto/3", &host).await?, 200);
+        assert_eq!(send(address, "POST", "/command/goto/0", &host).await?, 400);
+        assert_eq!(send(address, "POST", "/command/endcast", &host).await?, 200);
+        assert_eq!(send(address, "POST", "/command/stop", &host).await?, 404);
+        // and not for other sites, nor names rebound to this machine
+        let page = format!("{}Origin: https://example.com\r\n", host);
+        assert_eq!(send(address, "POST", "/command/next", &page).await?, 403);
+        assert_eq!(send(address, "POST", "/command/next", &format!("{}Origin: null\r\n", host)).await?, 403);
+        assert_eq!(send(address, "GET", "/status", "Host: photos.example.com\r\n").await?, 403);
+        assert_eq!(send(address, "GET", "/status", "").await?, 403);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(matches!(parse("next"), Some(ServerCommand::Next)));
+        assert!(matches!(parse("pageant"), Some(ServerCommand::Pageant)));
+        assert!(matches!(parse("endcast"), Some(ServerCommand::EndCast)));
+        assert!(parse("status").is_none());
+        assert!(parse("Next").is_none());
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::model::ServerCommand;
+use crate::server::upload::DEFAULT_MAX_UPLOAD_MIB;
+use crate::transport::Peer;
+
+/// Default limit on connections open at once
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+
+/// Default limit on request streams open at once on a connection
+pub const DEFAULT_MAX_STREAMS: usize = 16;
+
+/// Default rate of commands changing the display, per client per second
+pub const DEFAULT_RATE: f64 = 5.0;
+
+/// How much the server takes from its clients
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Largest image clients may upload, in bytes
+    pub max_upload: u64,
+    pub max_connections: usize,
+    pub max_streams: usize,
+    /// Commands changing the display a client may run per second, the
+    /// other classes going by it. 0 turns rate limiting off.
+    pub rate: f64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_upload: DEFAULT_MAX_UPLOAD_MIB * 1024 * 1024,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_streams: DEFAULT_MAX_STREAMS,
+            rate: DEFAULT_RATE,
+        }
+    }
+}
+
+/// Commands limited together
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CommandClass {
+    /// Commands changing the display, at the configured rate
+    Display,
+    /// Asking about or fetching the image on display, at four times it
+    Query,
+    /// Uploads and casts, at half of it
+    Transfer,
+    /// Asking to pair, at a twentieth of it
+    Pairing,
+}
+
+impl CommandClass {
+    pub fn of(command: &ServerCommand) -> CommandClass {
+        match command {
+            ServerCommand::Status | ServerCommand::Fetch { .. } => CommandClass::Query,
+            ServerCommand::Upload { .. } | ServerCommand::Cast { .. } | ServerCommand::EndCast => {
+                CommandClass::Transfer
+            }
+            _ => CommandClass::Display,
+        }
+    }
+    /// Tokens per second and bucket size of this class at `rate`. A
+    /// client may burst to twice the rate after keeping quiet.
+    fn bucket(self, rate: f64) -> (f64, f64) {
+        let per_second = match self {
+            CommandClass::Display => rate,
+            CommandClass::Query => rate * 4.0,
+            CommandClass::Transfer => rate / 2.0,
+            CommandClass::Pairing => rate / 20.0,
+        };
+        (per_second, (per_second * 2.0).max(1.0))
+    }
+}
+
+impl fmt::Display for CommandClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandClass::Display => write!(f, "display"),
+            CommandClass::Query => write!(f, "query"),
+            CommandClass::Transfer => write!(f, "transfer"),
+            CommandClass::Pairing => write!(f, "pairing"),
+        }
+    }
+}
+
+/// A request refused for coming too soon after the client's others
+#[derive(Debug)]
+pub struct RateLimited {
+    pub class: CommandClass,
+    /// Until the client may run another command of the class
+    pub wait: Duration,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rate limited: too many {} commands, try again in {:.1}s", self.class, self.wait.as_secs_f64())
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Refill at `per_second` up to `size` since last time, then take a
+    /// token if there is one. Otherwise tells how long until there is.
+    fn take(&mut self, per_second: f64, size: f64, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(size);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / per_second))
+        }
+    }
+}
+
+type Buckets = HashMap<CommandClass, Bucket>;
+
+/// Enforces `Limits` across every connection of the server
+#[derive(Clone)]
+pub struct Limiter {
+    limits: Limits,
+    connections: Arc<Semaphore>,
+    // by client, so reconnecting doesn't refill them
+    clients: Arc<Mutex<HashMap<String, Buckets>>>,
+}
+
+impl Limiter {
+    pub fn new(limits: Limits) -> Limiter {
+        Limiter {
+            limits,
+            connections: Arc::new(Semaphore::new(limits.max_connections)),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+    /// Room for another connection, held until the permit is dropped.
+    /// None if there are too many already.
+    pub fn connection(&self) -> Option<OwnedSemaphorePermit> {
+        self.connections.clone().try_acquire_owned().ok()
+    }
+    /// Limits of a connection from `peer`, known by its certificate or
+    /// else its address
+    pub fn client(&self, peer: &Peer) -> ClientLimits {
+        let client = match (&peer.fingerprint, peer.local) {
+            (Some(fingerprint), _) => fingerprint.clone(),
+            (None, true) => "local".to_string(),
+            (None, false) => peer
+                .address
+                .parse::<SocketAddr>()
+                .map_or(peer.address.clone(), |a| a.ip().to_string()),
+        };
+        self.forget_idle(Instant::now());
+        ClientLimits {
+            client,
+            streams: Arc::new(Semaphore::new(self.limits.max_streams)),
+            limiter: self.clone(),
+        }
+    }
+    /// Take a token for `class` from the buckets of `client`
+    fn check(&self, client: &str, class: CommandClass, now: Instant) -> Result<(), RateLimited> {
+        if self.limits.rate <= 0.0 {
+            return Ok(());
+        }
+        let (per_second, size) = class.bucket(self.limits.rate);
+        let mut clients = self.clients.lock().unwrap();
+        let bucket = clients
+            .entry(client.to_string())
+            .or_default()
+            .entry(class)
+            .or_insert(Bucket { tokens: size, updated: now });
+        bucket
+            .take(per_second, size, now)
+            .map_err(|wait| RateLimited { class, wait })
+    }
+    /// Drop clients whose buckets have all filled up again, they are
+    /// as good as new
+    fn forget_idle(&self, now: Instant) {
+        let rate = self.limits.rate;
+        self.clients.lock().unwrap().retain(|_, buckets| {
+            buckets.iter().any(|(class, bucket)| {
+                let (per_second, size) = class.bucket(rate);
+                let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens + elapsed * per_second < size
+            })
+        });
+    }
+}
+
+/// What a connection is held to. Its rate is shared with the client's
+/// other connections.
+#[derive(Clone)]
+pub struct ClientLimits {
+    client: String,
+    streams: Arc<Semaphore>,
+    limiter: Limiter,
+}
+
+impl ClientLimits {
+    /// Room for another request stream, held until the permit is
+    /// dropped. None if the connection has too many open.
+    pub fn stream(&self) -> Option<OwnedSemaphorePermit> {
+        self.streams.clone().try_acquire_owned().ok()
+    }
+    /// Whether the client may run `command` now
+    pub fn check(&self, command: &ServerCommand) -> Result<(), RateLimited> {
+        self.limiter.check(&self.client, CommandClass::of(command), Instant::now())
+    }
+    /// Whether the client may ask to pair now
+    pub fn check_pairing(&self) -> Result<(), RateLimited> {
+        self.limiter.check(&self.client, CommandClass::Pairing, Instant::now())
+    }
+    /// Who the limits are kept for: a certificate fingerprint, an IP
+    /// address or `local`
+    pub fn client(&self) -> &str {
+        &self.client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(address: &str) -> Peer {
+        Peer {
+            address: address.to_string(),
+            fingerprint: None,
+            local: false,
+        }
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = Limiter::new(Limits { rate: 5.0, ..Limits::default() });
+        let start = Instant::now();
+        // a burst of twice the rate, then one every 200ms
+        for _ in 0..10 {
+            assert!(limiter.check("a", CommandClass::Display, start).is_ok());
+        }
+        let limited = limiter.check("a", CommandClass::Display, start).unwrap_err();
+        assert_eq!(limited.class, CommandClass::Display);
+        assert_eq!(limited.wait, Duration::from_millis(200));
+        let later = start + Duration::from_millis(200);
+        assert!(limiter.check("a", CommandClass::Display, later).is_ok());
+        assert!(limiter.check("a", CommandClass::Display, later).is_err());
+        // other classes and clients have buckets of their own
+        assert!(limiter.check("a", CommandClass::Query, later).is_ok());
+        assert!(limiter.check("b", CommandClass::Display, later).is_ok());
+        // one pairing at a time, then one every four seconds
+        assert!(limiter.check("a", CommandClass::Pairing, later).is_ok());
+        assert!(limiter.check("a", CommandClass::Pairing, later).is_err());
+
+        // reconnecting from another port is the same client
+        let one = limiter.client(&peer("192.0.2.7:50000"));
+        let other = limiter.client(&peer("192.0.2.7:50001"));
+        assert_eq!(one.client, other.client);
+        // full buckets are forgotten
+        limiter.forget_idle(start + Duration::from_secs(5));
+        assert!(limiter.clients.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_connection_and_stream_limits() {
+        let limiter = Limiter::new(Limits { max_connections: 2, max_streams: 1, ..Limits::default() });
+        let first = limiter.connection();
+        let second = limiter.connection();
+        assert!(first.is_some() && second.is_some());
+        assert!(limiter.connection().is_none());
+        drop(first);
+        assert!(limiter.connection().is_some());
+
+        let client = limiter.client(&peer("192.0.2.7:50000"));
+        let stream = client.stream();
+        assert!(stream.is_some());
+        assert!(client.stream().is_none());
+        // every connection has streams of its own
+        assert!(limiter.client(&peer("192.0.2.7:50001")).stream().is_some());
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::{Arc, RwLock};
+
+use crossbeam_channel::Sender;
+use tokio::sync::broadcast;
+use tracing::{debug, error, info, warn};
+
+use crate::model::{Role, ServerEvent};
+use crate::server::{
+    allowlist::Allowlist, controller::Envelope, handlers::handle_connection, limits::Limiter,
+    pairing::Pairing, upload::Uploads,
+};
+use crate::transport::{Peer, ServerTransport};
+
+/// Takes in clients over a transport, decides what they may do and
+/// serves their connections.
+pub struct Listener {
+    transport: Box<dyn ServerTransport>,
+    allowlist: Option<Arc<RwLock<Allowlist>>>,
+    pairing: Option<Pairing>,
+    uploads: Uploads,
+    limiter: Limiter,
+    tx_req: Sender<Envelope>,
+    events: broadcast::Sender<ServerEvent>,
+}
+
+impl Listener {
+    /// With an `allowlist` only clients presenting a certificate on it
+    /// are served, and others may pair through `pairing`. Images
+    /// clients upload go to `uploads`. Connections beyond the limits of
+    /// `limiter` are dropped.
+    pub fn new(
+        transport: Box<dyn ServerTransport>,
+        allowlist: Option<Arc<RwLock<Allowlist>>>,
+        pairing: Option<Pairing>,
+        uploads: Uploads,
+        limiter: Limiter,
+        tx_req: Sender<Envelope>,
+        events: broadcast::Sender<ServerEvent>,
+    ) -> Listener {
+        Listener {
+            transport,
+            allowlist,
+            pairing,
+            uploads,
+            limiter,
+            tx_req,
+            events,
+        }
+    }
+
+    /// Check the client certificate against the allowlist, if there is
+    /// one. Returns the client's role, or why it is refused.
+    fn admit(&self, peer: &Peer) -> Result<Role, String> {
+        let Some(allowlist) = &self.allowlist else {
+            return Ok(Role::Controller);
+        };
+        // reaching a local socket takes permission to open it
+        if peer.local {
+            return Ok(Role::Controller);
+        }
+        let allowlist = allowlist.read().unwrap();
+        let role = allowlist.check(peer.fingerprint.as_deref())?;
+        if let Some(fingerprint) = &peer.fingerprint {
+            debug!("client certificate {} ({})", fingerprint, allowlist.name(fingerprint).unwrap_or_default());
+        }
+        Ok(role)
+    }
+
+    pub fn listen_task(mut self) {
+        tokio::spawn(async move {
+            while let Some((connection, peer)) = self.transport.accept().await {
+                info!("new connection: {}", peer.address);
+                let Some(permit) = self.limiter.connection() else {
+                    let max = self.limiter.limits().max_connections;
+                    warn!("refusing {}, {} connections are open", peer.address, max);
+                    continue;
+                };
+                let admission = self.admit(&peer);
+                match &admission {
+                    Ok(role) => debug!("client {} is a {}", peer.address, role),
+                    Err(reason) => warn!("client {}: {}", peer.address, reason),
+                }
+                let fut = handle_connection(
+                    self.tx_req.clone(),
+                    self.events.clone(),
+                    connection,
+                    admission,
+                    self.pairing.clone(),
+                    self.uploads.clone(),
+                    self.limiter.client(&peer),
+                );
+                tokio::spawn(async move {
+                    if let Err(e) = fut.await {
+                        error!("connection failed: {reason}", reason = e.to_string())
+                    }
+                    drop(permit);
+                });
+            }
+        });
+    }
+}
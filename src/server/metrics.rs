@@ -0,0 +1,212 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use axum::{
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use prometheus_client::{
+    encoding::{text::encode, EncodeLabelSet},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use crate::model::ServerCommand;
+
+/// Media type of the text the endpoint answers with
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// What the server keeps count of, for unattended displays. Recorded
+/// wherever it happens, hence kept in one place for the whole process.
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CommandLabels {
+    command: String,
+}
+
+type CommandHistograms = Family<CommandLabels, Histogram, fn() -> Histogram>;
+
+pub struct Metrics {
+    registry: Registry,
+    connections_accepted: Counter,
+    connections_active: Gauge,
+    requests: Family<CommandLabels, Counter>,
+    command_seconds: CommandHistograms,
+    fetch_bytes: Counter,
+    images_skipped: Counter,
+    pageant_advances: Counter,
+}
+
+/// Counts a connection as active for as long as it is kept
+pub struct Active(Gauge);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let mut registry = Registry::with_prefix("viewd");
+        let connections_accepted = Counter::default();
+        registry.register(
+            "connections_accepted",
+            "Client connections accepted",
+            connections_accepted.clone(),
+        );
+        let connections_active = Gauge::default();
+        registry.register(
+            "connections_active",
+            "Client connections open",
+            connections_active.clone(),
+        );
+        let requests = Family::default();
+        registry.register("requests", "Commands run, by command", requests.clone());
+        // 1ms to 8s
+        let command_seconds: CommandHistograms =
+            Family::new_with_constructor(|| Histogram::new(exponential_buckets(0.001, 2.0, 14)));
+        registry.register(
+            "command_duration_seconds",
+            "Time the display took to run a command, by command",
+            command_seconds.clone(),
+        );
+        let fetch_bytes = Counter::default();
+        registry.register("fetch_bytes", "Bytes of images sent to clients", fetch_bytes.clone());
+        let images_skipped = Counter::default();
+        registry.register(
+            "images_skipped",
+            "Images dropped from the list because they could not be loaded",
+            images_skipped.clone(),
+        );
+        let pageant_advances = Counter::default();
+        registry.register(
+            "pageant_advances",
+            "Images moved on to by pageant mode",
+            pageant_advances.clone(),
+        );
+        Metrics {
+            registry,
+            connections_accepted,
+            connections_active,
+            requests,
+            command_seconds,
+            fetch_bytes,
+            images_skipped,
+            pageant_advances,
+        }
+    }
+
+    /// Count a new connection, active until the returned guard is dropped
+    pub fn connection(&self) -> Active {
+        self.connections_accepted.inc();
+        self.connections_active.inc();
+        Active(self.connections_active.clone())
+    }
+
+    /// Count `command`, which took `elapsed` to run
+    pub fn command(&self, command: &ServerCommand, elapsed: Duration) {
+        let labels = CommandLabels {
+            command: command.to_string(),
+        };
+        self.requests.get_or_create(&labels).inc();
+        self.command_seconds
+            .get_or_create(&labels)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn fetched(&self, bytes: u64) {
+        self.fetch_bytes.inc_by(bytes);
+    }
+
+    pub fn skipped_image(&self) {
+        self.images_skipped.inc();
+    }
+
+    pub fn pageant_advanced(&self) {
+        self.pageant_advances.inc();
+    }
+
+    /// Everything in the OpenMetrics text format
+    pub fn encode(&self) -> Result<String> {
+        let mut text = String::new();
+        encode(&mut text, &self.registry).map_err(|e| anyhow!("Metrics Error: {}", e))?;
+        Ok(text)
+    }
+}
+
+/// An HTTP listener answering `GET /metrics` for Prometheus to scrape.
+/// It can't change anything, unlike the HTTP API.
+pub struct MetricsService {
+    listener: TcpListener,
+    router: Router,
+}
+
+impl MetricsService {
+    pub fn new(bind: &str) -> Result<MetricsService> {
+        let listener = std::net::TcpListener::bind(bind)?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        info!("metrics on {}", listener.local_addr()?);
+        let router = Router::new().route("/metrics", get(scrape));
+        Ok(MetricsService { listener, router })
+    }
+
+    pub fn listen_task(self) {
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(self.listener, self.router).await {
+                error!("metrics failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn scrape() -> impl IntoResponse {
+    match metrics().encode() {
+        Ok(text) => (StatusCode::OK, [(header::CONTENT_TYPE, CONTENT_TYPE)], text),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            [(header::CONTENT_TYPE, "text/plain")],
+            e.to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() -> Result<()> {
+        let metrics = Metrics::new();
+        let active = metrics.connection();
+        metrics.command(&ServerCommand::Next, Duration::from_millis(3));
+        metrics.command(&ServerCommand::Next, Duration::from_millis(5));
+        metrics.fetched(1024);
+        let text = metrics.encode()?;
+        assert!(text.contains("viewd_connections_accepted_total 1\n"), "{}", text);
+        assert!(text.contains("viewd_requests_total{command=\"Next\"} 2\n"), "{}", text);
+        assert!(text.contains("viewd_command_duration_seconds_count{command=\"Next\"} 2\n"), "{}", text);
+        assert!(text.contains("viewd_fetch_bytes_total 1024\n"), "{}", text);
+        assert!(text.contains("viewd_connections_active 1\n"), "{}", text);
+        drop(active);
+        assert!(metrics.encode()?.contains("viewd_connections_active 0\n"));
+        assert!(text.ends_with("# EOF\n"));
+        Ok(())
+    }
+}
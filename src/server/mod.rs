@@ -1,38 +1,175 @@
+mod control;
+mod http;
+mod metrics;
 mod controller;
 mod navigator;
 mod cursor;
 mod window;
 mod handlers;
+mod limits;
+mod listener;
 mod quic_service;
+mod tcp_service;
+mod unix_service;
 mod pageant;
+mod allowlist;
+mod pairing;
+mod tokens;
+mod upload;
 
 use std::{
-    path::Path,
-    sync::{Arc, Mutex},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
 };
-use bytes::Bytes;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use crossbeam_channel::unbounded;
+use tokio::sync::broadcast;
+use tracing::warn;
 
-use crate::{ server::controller::Controller, server::quic_service::QuicService};
+pub use crate::server::allowlist::Allowlist;
+pub use crate::server::limits::{Limits, DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_STREAMS, DEFAULT_RATE};
+pub use crate::server::upload::DEFAULT_MAX_UPLOAD_MIB;
+
+use crate::{
+    discovery::{Announcement, Announcer, DISCOVERY_PORT},
+    model::Role,
+    server::control::ControlSocket,
+    server::controller::{Controller, Envelope},
+    server::http::HttpService,
+    server::metrics::MetricsService,
+    server::limits::Limiter,
+    server::listener::Listener,
+    server::pairing::Pairing,
+    server::tokens::Tokens,
+    server::quic_service::QuicService,
+    server::tcp_service::TcpService,
+    server::unix_service::UnixService,
+    server::upload::Uploads,
+    tls::Identity,
+    transport::{address, ServerTransport, TransportKind},
+};
+
+/// Events kept for a connection that falls behind before the oldest
+/// are dropped.
+const EVENT_BACKLOG: usize = 64;
+
+/// Ways besides clients to drive the display, each off unless given
+#[derive(Debug, Clone, Default)]
+pub struct Endpoints {
+    /// Unix socket taking text commands from scripts on this machine
+    pub control: Option<PathBuf>,
+    /// Address of an HTTP listener answering with JSON
+    pub http: Option<String>,
+    /// Bearer tokens the HTTP listener admits, by default only callers
+    /// on this machine
+    pub http_tokens: Option<PathBuf>,
+    /// Address of an HTTP listener for Prometheus to scrape
+    pub metrics: Option<String>,
+    /// Name to answer clients looking for servers on the network with
+    pub discovery: Option<String>,
+}
+
+/// Who is let in when the server only admits known clients
+#[derive(Debug)]
+pub struct Admission {
+    pub allowlist: Allowlist,
+    /// Most a pairing client is granted, whatever it asks for
+    pub pair_role: Role,
+}
 
 /// Viewd Server to handle network requests and issue commands to SDL2
 pub struct Server {
-    quic: QuicService,
+    listener: Listener,
+    control_socket: Option<ControlSocket>,
+    http: Option<HttpService>,
+    metrics: Option<MetricsService>,
+    announcer: Option<Announcer>,
     control: Controller,
     exiting: Arc<Mutex<bool>>,
 }
 
 impl Server {
-    pub fn new(bind: String, path: &Path) -> Result<Self> {
-        let (tx_res, rx_res) = unbounded::<Bytes>();
-        let (tx_req, rx_req) = unbounded::<Bytes>();
+    /// Serves clients reaching any of `bind` over `transport`, addresses
+    /// or for a Unix socket its path, and whatever else `endpoints` asks
+    /// for.
+    pub fn new(
+        bind: Vec<String>,
+        transport: TransportKind,
+        path: &Path,
+        identity: &Identity,
+        admission: Option<Admission>,
+        limits: Limits,
+        endpoints: Endpoints,
+    ) -> Result<Self> {
+        let (tx_req, rx_req) = unbounded::<Envelope>();
+        let (events, _) = broadcast::channel(EVENT_BACKLOG);
         let exiting = Arc::new(Mutex::new(false));
-        let mut control = Controller::new(path, rx_req, tx_res, exiting.clone())?;
-        let quic = QuicService::new(bind, tx_req, rx_res)?;
+        // clients can only pair with a server that keeps an allowlist
+        let (allowlist, pairing) = match admission {
+            Some(Admission { allowlist, pair_role }) => {
+                let allowlist = Arc::new(RwLock::new(allowlist));
+                (Some(allowlist.clone()), Some(Pairing::new(allowlist, pair_role)))
+            }
+            None => (None, None),
+        };
+        let mut control =
+            Controller::new(path, rx_req, events.clone(), pairing.clone(), exiting.clone())?;
+        let uploads = Uploads::new(path, limits.max_upload);
+        let client_auth = allowlist.is_some();
+        let addresses = match transport {
+            TransportKind::Unix => Vec::new(),
+            _ => address::resolve_binds(&bind)?,
+        };
+        // a missing announcement only means typing the address
+        let announcer = match (endpoints.discovery, transport) {
+            (None, _) | (_, TransportKind::Unix) => None,
+            (Some(name), transport) => announcer(&name, &addresses, transport, identity)
+                .inspect_err(|e| warn!("not answering discovery: {}", e))
+                .ok(),
+        };
+        let transport: Box<dyn ServerTransport> = match transport {
+            TransportKind::Unix => match bind.as_slice() {
+                [path] => Box::new(UnixService::new(Path::new(path))?),
+                _ => return Err(anyhow!("Bind Error: a Unix socket has one path")),
+            },
+            kind => {
+                let mut transports: Vec<Box<dyn ServerTransport>> = Vec::new();
+                for address in &addresses {
+                    let address = address.to_string();
+                    transports.push(match kind {
+                        TransportKind::Tcp => Box::new(TcpService::new(&address, identity, client_auth)?),
+                        _ => Box::new(QuicService::new(&address, identity, client_auth, limits.max_streams)?),
+                    });
+                }
+                address::merge(transports)
+            }
+        };
+        let control_socket = endpoints
+            .control
+            .map(|path| ControlSocket::new(&path, tx_req.clone()))
+            .transpose()?;
+        let limiter = Limiter::new(limits);
+        let http_tokens = endpoints.http_tokens.as_deref().map(Tokens::load).transpose()?;
+        let http = endpoints
+            .http
+            .map(|bind| {
+                let limiter = limiter.clone();
+                HttpService::new(&bind, http_tokens, allowlist.is_some(), limiter, tx_req.clone(), events.clone())
+            })
+            .transpose()?;
+        let metrics = endpoints
+            .metrics
+            .map(|bind| MetricsService::new(&bind))
+            .transpose()?;
+        let listener = Listener::new(transport, allowlist, pairing, uploads, limiter, tx_req, events);
 	control.next()?;
         let s = Server {
-            quic,
+            listener,
+            control_socket,
+            http,
+            metrics,
+            announcer,
             control,
             exiting,
         };
@@ -41,7 +178,19 @@ impl Server {
     /// start event loop
     pub fn run(mut self) -> Result<()> {
         // listen for network connections
-        self.quic.listen_task();
+        self.listener.listen_task();
+        if let Some(control_socket) = self.control_socket.take() {
+            control_socket.listen_task();
+        }
+        if let Some(http) = self.http.take() {
+            http.listen_task();
+        }
+        if let Some(metrics) = self.metrics.take() {
+            metrics.listen_task();
+        }
+        if let Some(announcer) = self.announcer.take() {
+            announcer.listen_task();
+        }
         loop {
             if *self.exiting.lock().unwrap() {
                 break Ok(());
@@ -52,7 +201,29 @@ impl Server {
             self.control.handle_events();
 	    // handle pageant mode
 	    self.control.pageant();
+	    // show or hide the pairing PIN
+	    self.control.pairing();
         }
     }
 }
 
+
+/// Answer discovery queries for a server listening on `addresses`.
+/// Clients are pointed at the address they asked on if the server
+/// listens on all of them, else at its first IPv4 address, as queries
+/// only arrive over IPv4. A server only reachable over loopback has
+/// nothing to announce to the network.
+fn announcer(
+    name: &str,
+    addresses: &[SocketAddr],
+    transport: TransportKind,
+    identity: &Identity,
+) -> Result<Announcer> {
+    let bind = addresses
+        .iter()
+        .find(|a| a.ip().is_unspecified())
+        .or_else(|| addresses.iter().find(|a| a.is_ipv4() && !a.ip().is_loopback()))
+        .ok_or(anyhow!("Discovery Error: no IPv4 address to announce beyond loopback"))?;
+    let announcement = Announcement::new(name, *bind, transport, &identity.fingerprint()?);
+    Announcer::new(DISCOVERY_PORT, &announcement)
+}
@@ -1,8 +1,6 @@
-use anyhow::{anyhow, Result};
-use std::fs;
+use anyhow::Result;
 use std::path::Path;
 use std::path::PathBuf;
-use tracing::debug;
 
 use crate::server::cursor::PathCursor;
 
@@ -11,6 +9,7 @@ use crate::server::cursor::PathCursor;
 pub struct Navigator {
     pub cursor: PathCursor,
     pub image: PathBuf,
+    dir: PathBuf,
 }
 
 impl Navigator {
@@ -24,6 +23,7 @@ impl Navigator {
 	let n = Self {
 	    cursor,
 	    image,
+	    dir: path.to_path_buf(),
 	};
 	Ok(n)
     }
@@ -39,17 +39,38 @@ impl Navigator {
 	self.image = path.to_path_buf();
 	Some(path)
     }
+    /// add a new image to the list
+    pub fn insert(&mut self, path: PathBuf) -> usize {
+	self.cursor.insert(path)
+    }
+    /// the image at `index`, staying where we are
+    pub fn get(&self, index: usize) -> Option<&Path> {
+	self.cursor.get(index).map(|p| p.as_path())
+    }
+    /// jump to the image at `index` and return it
+    pub fn show(&mut self, index: usize) -> Option<&Path> {
+	let path = self.cursor.seek(index)?;
+	self.image = path.to_path_buf();
+	Some(path)
+    }
+    /// place of the image on display in the list
+    pub fn index(&self) -> Option<usize> {
+	self.cursor.position(&self.image)
+    }
+    /// directory the images are in
+    pub fn dir(&self) -> &Path {
+	&self.dir
+    }
     /// remove current cursor path from list
     pub fn delete(&mut self) {
 	self.cursor.remove();
     }
+    /// number of images left to navigate
+    pub fn count(&self) -> usize {
+	self.cursor.count()
+    }
     pub fn image_path(&self) -> PathBuf {
 	let path = &self.image;
 	path.to_path_buf()
     }
-    pub fn image_data(&self) -> Result<Vec<u8>> {
-	let path = &self.image;
-	debug!("opening file at {:?}", path);
-	fs::read(path).map_err(|e| anyhow!("Image Data Error: {}", e))
-    }
 }
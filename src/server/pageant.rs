@@ -23,6 +23,9 @@ impl PageantMode {
             self.instant = Some(Instant::now());
         }
     }
+    pub fn is_enabled(&self) -> bool {
+        self.instant.is_some()
+    }
     pub fn set_instant(&mut self) {
         self.instant = Some(Instant::now());
     }
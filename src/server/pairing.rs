@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+
+use crate::model::Role;
+use crate::server::allowlist::Allowlist;
+use crate::tls::{provider, Identity};
+
+/// How long a PIN stays on screen and can be used
+pub const PIN_LIFETIME: Duration = Duration::from_secs(120);
+/// Wrong PINs tolerated before the PIN is withdrawn
+const MAX_ATTEMPTS: u32 = 3;
+/// Digits in a PIN
+const PIN_DIGITS: usize = 6;
+/// Wait before pairing again after one failed, doubling with each
+/// further failure
+const COOLDOWN: Duration = Duration::from_secs(2);
+/// Longest wait before pairing again
+const MAX_COOLDOWN: Duration = Duration::from_secs(600);
+/// How long a source's failures are held against it
+const FORGET_AFTER: Duration = Duration::from_secs(3600);
+
+/// A PIN on display for one pairing client
+#[derive(Debug)]
+struct Pin {
+    ticket: u64,
+    // who is pairing, as `ClientLimits::client` names it
+    source: String,
+    code: String,
+    issued: Instant,
+    attempts: u32,
+}
+
+impl Pin {
+    fn is_expired(&self) -> bool {
+        self.issued.elapsed() >= PIN_LIFETIME
+    }
+}
+
+/// Pairings in a row that ended without a credential
+#[derive(Debug, Default, Clone, Copy)]
+struct Strikes {
+    count: u32,
+    last: Option<Instant>,
+}
+
+impl Strikes {
+    fn add(&mut self, now: Instant) {
+        self.count = self.count.saturating_add(1);
+        self.last = Some(now);
+    }
+    /// How long until pairing may start again, if it may not yet
+    fn wait(&self, now: Instant) -> Option<Duration> {
+        let last = self.last?;
+        let cooldown = COOLDOWN
+            .saturating_mul(1 << self.count.saturating_sub(1).min(16))
+            .min(MAX_COOLDOWN);
+        cooldown.checked_sub(now.saturating_duration_since(last)).filter(|w| !w.is_zero())
+    }
+}
+
+/// Failed pairings, of all clients and of each source, so that PINs
+/// can't be guessed by pairing over and over
+#[derive(Debug, Default)]
+struct Failures {
+    all: Strikes,
+    sources: HashMap<String, Strikes>,
+}
+
+impl Failures {
+    fn add(&mut self, source: &str, now: Instant) {
+        self.all.add(now);
+        self.sources.entry(source.to_string()).or_default().add(now);
+    }
+    fn wait(&mut self, source: &str, now: Instant) -> Option<Duration> {
+        self.sources
+            .retain(|_, strikes| strikes.last.is_some_and(|last| now.saturating_duration_since(last) < FORGET_AFTER));
+        let mine = self.sources.get(source).and_then(|strikes| strikes.wait(now));
+        mine.max(self.all.wait(now))
+    }
+}
+
+/// Pairing state shared by connections and the display. At most one
+/// client pairs at a time; it is given a ticket naming its PIN.
+#[derive(Debug, Clone)]
+pub struct Pairing {
+    pin: Arc<Mutex<Option<Pin>>>,
+    failures: Arc<Mutex<Failures>>,
+    tickets: Arc<AtomicU64>,
+    allowlist: Arc<RwLock<Allowlist>>,
+    role: Role,
+}
+
+impl Pairing {
+    /// Paired clients are added to `allowlist`, with at most `role`.
+    pub fn new(allowlist: Arc<RwLock<Allowlist>>, role: Role) -> Self {
+        Self {
+            pin: Arc::new(Mutex::new(None)),
+            failures: Arc::new(Mutex::new(Failures::default())),
+            tickets: Arc::new(AtomicU64::new(0)),
+            allowlist,
+            role,
+        }
+    }
+    /// Put a fresh PIN on display for a client from `source`. Fails
+    /// while another client's PIN is, and for a while after pairing
+    /// failed, longer the more often it did.
+    pub fn start(&self, source: &str) -> Result<u64> {
+        self.start_at(source, Instant::now())
+    }
+    fn start_at(&self, source: &str, now: Instant) -> Result<u64> {
+        let mut pin = self.pin.lock().unwrap();
+        if let Some(pin) = pin.as_ref().filter(|p| p.is_expired()) {
+            self.failures.lock().unwrap().add(&pin.source, now);
+        }
+        if pin.as_ref().is_some_and(|p| !p.is_expired()) {
+            return Err(anyhow!("another client is pairing, try again later"));
+        }
+        *pin = None;
+        if let Some(wait) = self.failures.lock().unwrap().wait(source, now) {
+            return Err(anyhow!("pairing failed recently, try again in {}s", wait.as_secs() + 1));
+        }
+        let ticket = self.tickets.fetch_add(1, Ordering::Relaxed) + 1;
+        *pin = Some(Pin {
+            ticket,
+            source: source.to_string(),
+            code: random_pin()?,
+            issued: Instant::now(),
+            attempts: 0,
+        });
+        Ok(ticket)
+    }
+    /// The PIN to show, if one is live
+    pub fn pin(&self) -> Option<String> {
+        let mut pin = self.pin.lock().unwrap();
+        if pin.as_ref().is_some_and(|p| p.is_expired()) {
+            *pin = None;
+        }
+        pin.as_ref().map(|p| p.code.clone())
+    }
+    /// Check the PIN entered for `ticket`. When it matches a credential
+    /// is issued to `name` and allowed in with the role it asked for,
+    /// lowered to the server's. Otherwise the error says whether the
+    /// client may try again.
+    pub fn complete(&self, ticket: u64, code: &str, name: &str, asked: Role) -> Result<(Identity, Role), Attempt> {
+        let mut guard = self.pin.lock().unwrap();
+        let pin = match guard.as_mut() {
+            Some(pin) if pin.ticket == ticket && !pin.is_expired() => pin,
+            _ => {
+                drop(guard);
+                self.cancel(ticket);
+                return Err(Attempt::Over("the PIN has expired".to_string()));
+            }
+        };
+        if !same_pin(&pin.code, code) {
+            pin.attempts += 1;
+            if pin.attempts >= MAX_ATTEMPTS {
+                self.failures.lock().unwrap().add(&pin.source, Instant::now());
+                *guard = None;
+                return Err(Attempt::Over("too many wrong PINs".to_string()));
+            }
+            let left = MAX_ATTEMPTS - pin.attempts;
+            return Err(Attempt::Retry(format!("wrong PIN, {} attempts left", left)));
+        }
+        let source = pin.source.clone();
+        *guard = None;
+        drop(guard);
+        // a client that knew the PIN is no attacker
+        let mut failures = self.failures.lock().unwrap();
+        failures.all = Strikes::default();
+        failures.sources.remove(&source);
+        drop(failures);
+        let role = asked.min(self.role);
+        let credential = || -> Result<(Identity, Role)> {
+            let identity = Identity::generate_for(vec![name.to_string()])?;
+            self.allowlist
+                .write()
+                .unwrap()
+                .add(&identity.fingerprint()?, name, role)?;
+            Ok((identity, role))
+        };
+        credential().map_err(|e| Attempt::Over(e.to_string()))
+    }
+    /// Take the PIN of `ticket` off the display, if it is still there.
+    /// The client left without pairing, which counts as a failure.
+    pub fn cancel(&self, ticket: u64) {
+        let mut pin = self.pin.lock().unwrap();
+        if let Some(p) = pin.as_ref().filter(|p| p.ticket == ticket) {
+            self.failures.lock().unwrap().add(&p.source, Instant::now());
+            *pin = None;
+        }
+    }
+}
+
+/// Why a PIN was not accepted
+#[derive(Debug)]
+pub enum Attempt {
+    /// The PIN was wrong but stays valid
+    Retry(String),
+    /// The PIN is gone
+    Over(String),
+}
+
+/// Make a client supplied name safe to put in a certificate and the
+/// allowlist file.
+pub fn sanitize_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '-' })
+        .take(63)
+        .collect();
+    let name = name.trim_matches(|c| c == '-' || c == '.');
+    if name.is_empty() {
+        "client".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Whether the PIN `entered` is `code`, taking as long whichever digit
+/// is wrong
+fn same_pin(code: &str, entered: &str) -> bool {
+    code.len() == entered.len()
+        && code.bytes().zip(entered.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn random_pin() -> Result<String> {
+    let mut bytes = [0u8; PIN_DIGITS];
+    provider()
+        .secure_random
+        .fill(&mut bytes)
+        .map_err(|_| anyhow!("Pairing Error: no randomness for a PIN"))?;
+    // the bias of % 10 on a byte is too small to matter for a PIN
+    Ok(bytes.iter().map(|b| char::from(b'0' + b % 10)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairing(dir: &tempfile::TempDir, role: Role) -> Result<Pairing> {
+        let allowlist = Allowlist::load(&dir.path().join("allowlist"))?;
+        Ok(Pairing::new(Arc::new(RwLock::new(allowlist)), role))
+    }
+
+    #[test]
+    fn test_pair() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let pairing = pairing(&dir, Role::Controller)?;
+        assert!(pairing.pin().is_none());
+        let ticket = pairing.start("192.0.2.7")?;
+        let code = pairing.pin().unwrap();
+        assert_eq!(code.len(), PIN_DIGITS);
+        // one client at a time
+        assert!(pairing.start("192.0.2.8").is_err());
+
+        let (identity, role) = pairing.complete(ticket, &code, "tablet", Role::Viewer).unwrap();
+        assert_eq!(role, Role::Viewer);
+        assert!(pairing.pin().is_none());
+        let fingerprint = identity.fingerprint()?;
+        let allowlist = Allowlist::load(&dir.path().join("allowlist"))?;
+        assert_eq!(allowlist.check(Some(&fingerprint)), Ok(Role::Viewer));
+        assert_eq!(allowlist.name(&fingerprint), Some("tablet"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pair_role_lowered() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let pairing = pairing(&dir, Role::Viewer)?;
+        let ticket = pairing.start("192.0.2.7")?;
+        let code = pairing.pin().unwrap();
+        // asking doesn't make a client a controller
+        let (identity, role) = pairing.complete(ticket, &code, "phone", Role::Controller).unwrap();
+        assert_eq!(role, Role::Viewer);
+        let allowlist = Allowlist::load(&dir.path().join("allowlist"))?;
+        assert_eq!(allowlist.check(Some(&identity.fingerprint()?)), Ok(Role::Viewer));
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrong_pin() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let pairing = pairing(&dir, Role::Controller)?;
+        let ticket = pairing.start("192.0.2.7")?;
+        for _ in 1..MAX_ATTEMPTS {
+            let attempt = pairing.complete(ticket, "nope", "tablet", Role::Controller);
+            assert!(matches!(attempt, Err(Attempt::Retry(_))));
+        }
+        let attempt = pairing.complete(ticket, "nope", "tablet", Role::Controller);
+        assert!(matches!(attempt, Err(Attempt::Over(_))));
+        assert!(pairing.pin().is_none());
+        // no fresh PIN to guess right away, from anywhere
+        assert!(pairing.start("192.0.2.7").is_err());
+        assert!(pairing.start("192.0.2.8").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pairing_cooldown() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let pairing = pairing(&dir, Role::Controller)?;
+        // leaving without the PIN counts as failing
+        let ticket = pairing.start("192.0.2.7")?;
+        pairing.cancel(ticket);
+        assert!(pairing.start("192.0.2.7").is_err());
+        assert!(pairing.pin().is_none());
+
+        let mut failures = Failures::default();
+        let start = Instant::now();
+        failures.add("192.0.2.7", start);
+        assert_eq!(failures.wait("192.0.2.7", start), Some(COOLDOWN));
+        assert_eq!(failures.wait("192.0.2.8", start), Some(COOLDOWN));
+        let now = start + COOLDOWN;
+        assert_eq!(failures.wait("192.0.2.7", now), None);
+        // each failure doubles the wait, more so for the source failing
+        failures.add("192.0.2.7", now);
+        failures.add("192.0.2.7", now);
+        failures.add("192.0.2.8", now);
+        assert_eq!(failures.wait("192.0.2.9", now), Some(COOLDOWN * 8));
+        failures.all = Strikes { count: 1, last: Some(now) };
+        assert_eq!(failures.wait("192.0.2.7", now), Some(COOLDOWN * 4));
+        assert_eq!(failures.wait("192.0.2.8", now), Some(COOLDOWN));
+        // up to a limit, and forgotten after a while
+        failures.sources.get_mut("192.0.2.7").unwrap().count = 40;
+        assert_eq!(failures.wait("192.0.2.7", now), Some(MAX_COOLDOWN));
+        failures.wait("192.0.2.7", now + FORGET_AFTER);
+        assert!(failures.sources.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_same_pin() {
+        assert!(same_pin("042917", "042917"));
+        assert!(!same_pin("042917", "042918"));
+        assert!(!same_pin("042917", "04291"));
+        assert!(!same_pin("042917", ""));
+    }
+
+    #[test]
+    fn test_sanitize_name() {
+        assert_eq!(sanitize_name("Anna's iPhone"), "Anna-s-iPhone");
+        assert_eq!(sanitize_name("tablet\n4f:1c bad"), "tablet-4f-1c-bad");
+        assert_eq!(sanitize_name("  "), "client");
+    }
+}
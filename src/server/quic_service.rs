@@ -1,57 +1,55 @@
-use anyhow::Result;
-use bytes::Bytes;
-use crossbeam_channel::{Receiver, Sender};
-use s2n_quic::Server;
-use tracing::{error, info};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use s2n_quic::{
+    provider::{limits::Limits, tls},
+    Server,
+};
 
-use crate::server::handlers::handle_connection;
-
-/// NOTE: this certificate is to be used for demonstration purposes only!
-pub static CERT_PEM: &str = include_str!(concat!("../../tls/cert.pem"));
-/// NOTE: this certificate is to be used for demonstration purposes only!
-pub static KEY_PEM: &str = include_str!(concat!("../../tls/key.pem"));
+use crate::server::allowlist::{PeerCertificate, PeerCertificates};
+use crate::tls::{server_config, Identity};
+use crate::transport::{datagram_endpoint, Connection, Peer, ServerTransport};
 
 #[derive(Debug)]
 /// Server side of Quic connection
 pub struct QuicService {
     server: Server,
-    tx_req: Sender<Bytes>,
-    rx_res: Receiver<Bytes>
 }
 
 impl QuicService {
-    pub fn new(
-        bind: String,
-	tx_req: Sender<Bytes>, rx_res: Receiver<Bytes>
-    ) -> Result<QuicService> {
-        info! {"\n{}", CERT_PEM};
+    /// Listen on `bind` presenting `identity`. With `client_auth`
+    /// clients are asked for their certificate. Clients can't open more
+    /// than `max_streams` request streams at once.
+    pub fn new(bind: &str, identity: &Identity, client_auth: bool, max_streams: usize) -> Result<QuicService> {
+        let tls = tls::rustls::Server::from(server_config(identity, client_auth)?);
+        // the client waits for credit instead of having streams refused
+        let limits = Limits::new()
+            .with_max_open_remote_bidirectional_streams(max_streams as u64)
+            .map_err(|e| anyhow!("Limits Error: {}", e))?;
         let server = Server::builder()
-            .with_tls((CERT_PEM, KEY_PEM))?
-            .with_io(bind.as_str())?
+            .with_tls(tls)?
+            .with_limits(limits)?
+            .with_event(PeerCertificates)?
+            .with_datagram(datagram_endpoint()?)?
+            .with_io(bind)?
             .start()?;
-        let server = QuicService {
-            server,
-	    tx_req,
-	    rx_res,
-        };
-        Ok(server)
+        Ok(QuicService { server })
     }
+}
 
-    pub fn listen_task(mut self) {
-        tokio::spawn(async move {
-            while let Some(connection) = self.server.accept().await {
-                info! {
-                    "new connection ({}): {}",
-                    connection.id(),
-                    connection.remote_addr().unwrap()
-                };
-                let fut = handle_connection(self.tx_req.clone(), self.rx_res.clone(), connection);
-                tokio::spawn(async move {
-                    if let Err(e) = fut.await {
-                        error!("connection failed: {reason}", reason = e.to_string())
-                    }
-                });
-            }
-        });
+#[async_trait]
+impl ServerTransport for QuicService {
+    async fn accept(&mut self) -> Option<(Connection, Peer)> {
+        let connection = self.server.accept().await?;
+        let address = connection.remote_addr().map(|a| a.to_string()).unwrap_or_default();
+        let fingerprint = connection
+            .query_event_context(|peer: &PeerCertificate| peer.fingerprint.clone())
+            .ok()
+            .flatten();
+        let peer = Peer {
+            address,
+            fingerprint,
+            local: false,
+        };
+        Some((Connection::quic(connection), peer))
     }
 }
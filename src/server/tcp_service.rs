@@ -0,0 +1,81 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tracing::warn;
+
+use crate::tls::{fingerprint, server_config, Identity};
+use crate::transport::mux::{self, Side};
+use crate::transport::{Connection, Peer, ServerTransport, ACCEPT_BACKOFF};
+
+/// Time a client gets to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Server side of TLS over TCP, for networks that block UDP
+pub struct TcpService {
+    incoming: mpsc::UnboundedReceiver<(Connection, Peer)>,
+}
+
+impl TcpService {
+    /// Listen on `bind` presenting `identity`. With `client_auth`
+    /// clients are asked for their certificate.
+    pub fn new(bind: &str, identity: &Identity, client_auth: bool) -> Result<TcpService> {
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(identity, client_auth)?));
+        let listener = std::net::TcpListener::bind(bind)?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let (tx, incoming) = mpsc::unbounded_channel();
+        // handshakes run side by side, so a slow client holds up no one
+        tokio::spawn(async move {
+            while !tx.is_closed() {
+                let (stream, remote) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("accept failed: {}", e);
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(e)) => {
+                            warn!("TLS handshake with {} failed: {}", remote, e);
+                            return;
+                        }
+                        Err(_) => {
+                            warn!("TLS handshake with {} timed out", remote);
+                            return;
+                        }
+                    };
+                    let fingerprint = stream
+                        .get_ref()
+                        .1
+                        .peer_certificates()
+                        .and_then(|chain| chain.first().map(|der| fingerprint(der)));
+                    let peer = Peer {
+                        address: remote.to_string(),
+                        fingerprint,
+                        local: false,
+                    };
+                    tx.send((mux::connection(stream, Side::Listener), peer)).ok();
+                });
+            }
+        });
+        Ok(TcpService { incoming })
+    }
+}
+
+#[async_trait]
+impl ServerTransport for TcpService {
+    async fn accept(&mut self) -> Option<(Connection, Peer)> {
+        self.incoming.recv().await
+    }
+}
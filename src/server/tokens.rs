@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+use sha2::{Digest, Sha256};
+
+use crate::model::Role;
+
+/// Bearer tokens admitted to the HTTP API, read from a file with one
+/// `token [role]` per line. The role is `viewer` or `controller`, the
+/// default. Lines starting with `#` are comments.
+#[derive(Debug, Default)]
+pub struct Tokens {
+    // by SHA-256 of the token, so looking one up doesn't time the
+    // comparison of secrets
+    roles: HashMap<[u8; 32], Role>,
+}
+
+impl Tokens {
+    pub fn load(path: &Path) -> Result<Tokens> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Token Error: read {}: {}", path.display(), e))?;
+        let tokens = Self::parse(&text)?;
+        if tokens.roles.is_empty() {
+            bail!("Token Error: no tokens in {}", path.display());
+        }
+        Ok(tokens)
+    }
+    fn parse(text: &str) -> Result<Tokens> {
+        let mut roles = HashMap::new();
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let mut words = line.split_whitespace();
+            let token = words.next().unwrap_or_default();
+            // they travel in URLs unescaped
+            if !token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                bail!("Token Error: tokens may only hold letters, digits, - and _");
+            }
+            let role = match words.next() {
+                Some(role) => role.parse()?,
+                None => Role::Controller,
+            };
+            roles.insert(digest(token), role);
+        }
+        Ok(Tokens { roles })
+    }
+    /// Role of whoever presents `token`, None if it isn't one of ours
+    pub fn role(&self, token: &str) -> Option<Role> {
+        self.roles.get(&digest(token)).copied()
+    }
+    /// What to tell the holder of `token` apart by, like a certificate
+    /// fingerprint and without giving the token away
+    pub fn id(token: &str) -> String {
+        let digest = digest(token);
+        let hex: Vec<String> = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        format!("token:{}", hex.join(""))
+    }
+}
+
+fn digest(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens() -> Result<()> {
+        let tokens = Tokens::parse(
+            "# kitchen tablet\n\
+             k1tchen-7f3a viewer\n\
+             \n\
+             automation_91c2\n",
+        )?;
+        assert_eq!(tokens.role("k1tchen-7f3a"), Some(Role::Viewer));
+        assert_eq!(tokens.role("automation_91c2"), Some(Role::Controller));
+        assert_eq!(tokens.role("k1tchen"), None);
+        assert_eq!(tokens.role(""), None);
+        assert_ne!(Tokens::id("k1tchen-7f3a"), Tokens::id("automation_91c2"));
+        assert!(Tokens::parse("a&b=c\n").is_err());
+        assert!(Tokens::parse("abc admin\n").is_err());
+        Ok(())
+    }
+}
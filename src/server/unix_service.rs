@@ -0,0 +1,121 @@
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use tokio::net::UnixListener;
+use tracing::warn;
+
+use crate::transport::mux::{self, Side};
+use crate::transport::{Connection, Peer, ServerTransport, ACCEPT_BACKOFF};
+
+/// Server side of a Unix socket, for clients on the same machine. There
+/// is no TLS, whoever may open the socket is let in.
+pub struct UnixService {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixService {
+    /// Listen on a socket at `path`, as `bind` does.
+    pub fn new(path: &Path) -> Result<UnixService> {
+        Ok(UnixService {
+            listener: bind(path)?,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+/// Listen on a socket at `path` that only this user may open, replacing
+/// one left behind by a server that is no longer running. The socket is
+/// made in a directory only this user may enter and moved into place once
+/// its permissions are set, so nobody can connect to it in between.
+pub fn bind(path: &Path) -> Result<UnixListener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!("Socket Error: {} exists and is not a socket", path.display());
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            bail!("Socket Error: a server is already listening on {}", path.display());
+        }
+        std::fs::remove_file(path)?;
+    }
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Socket Error: {} is not a file", path.display()))?;
+    let private = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .map_err(|e| anyhow!("Socket Error: {}: {}", private.display(), e))?;
+    let staged = private.join(name);
+    let listener = UnixListener::bind(&staged)
+        .map_err(|e| anyhow!("Socket Error: {}: {}", path.display(), e))
+        .and_then(|listener| {
+            std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+            std::fs::rename(&staged, path)?;
+            Ok(listener)
+        });
+    std::fs::remove_file(&staged).ok();
+    std::fs::remove_dir(&private).ok();
+    listener
+}
+
+#[async_trait]
+impl ServerTransport for UnixService {
+    async fn accept(&mut self) -> Option<(Connection, Peer)> {
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, _)) => {
+                    let peer = Peer {
+                        address: self.path.display().to_string(),
+                        fingerprint: None,
+                        local: true,
+                    };
+                    return Some((mux::connection(stream, Side::Listener), peer));
+                }
+                Err(e) => {
+                    warn!("accept failed: {}", e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for UnixService {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("viewd.sock");
+
+        let listener = bind(&path).unwrap();
+        let metadata = std::fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert!(bind(&path).is_err());
+
+        // left behind by a server that is gone
+        drop(listener);
+        bind(&path).unwrap();
+
+        let file = dir.path().join("photos.txt");
+        std::fs::write(&file, "keep").unwrap();
+        assert!(bind(&file).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep");
+    }
+}
@@ -0,0 +1,124 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+/// Default limit on the size of an uploaded image, in MiB
+pub const DEFAULT_MAX_UPLOAD_MIB: u64 = 100;
+
+/// Where uploaded images are stored and how big they may be
+#[derive(Debug, Clone)]
+pub struct Uploads {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl Uploads {
+    /// Store images of up to `max_size` bytes in `dir`.
+    pub fn new(dir: &Path, max_size: u64) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            max_size,
+        }
+    }
+    /// Where an upload named `name` is stored
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+    /// Copy `size` bytes from `reader` into the directory under `name`.
+    /// The file only gets its name once complete, and an existing file
+    /// is never replaced.
+    pub async fn receive<R>(&self, reader: &mut R, name: &str, size: u64) -> Result<PathBuf>
+    where
+        R: AsyncRead + Unpin,
+    {
+        // the name comes from the client, keep it out of other directories
+        if Path::new(name).file_name() != Some(Path::new(name).as_os_str()) || name.starts_with('.') {
+            bail!("Upload Error: {:?} is not a plain file name", name);
+        }
+        self.check_size(size)?;
+        let path = self.path(name);
+        if fs::try_exists(&path).await? {
+            bail!("Upload Error: {} already exists", name);
+        }
+        // hidden until complete, and claimed so concurrent uploads of
+        // the same name don't write into each other
+        let part = self.dir.join(format!(".{}.upload", name));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&part)
+            .await
+            .map_err(|e| anyhow!("Upload Error: {}: {}", name, e))?;
+        let copy = async {
+            let received = tokio::io::copy(&mut reader.take(size), &mut file).await?;
+            if received != size {
+                bail!("Upload Error: stream ended after {} of {} bytes", received, size);
+            }
+            file.flush().await?;
+            // a hard link fails rather than replace a file created meanwhile
+            fs::hard_link(&part, &path)
+                .await
+                .map_err(|e| anyhow!("Upload Error: {}: {}", name, e))
+        };
+        let result = copy.await;
+        fs::remove_file(&part).await.ok();
+        result.map(|_| path)
+    }
+    /// Read `size` bytes from `reader` into memory, for an image that is
+    /// shown without being stored. The same size limit applies.
+    pub async fn read<R>(&self, reader: &mut R, size: u64) -> Result<Vec<u8>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        self.check_size(size)?;
+        let mut data = Vec::with_capacity(size as usize);
+        reader.take(size).read_to_end(&mut data).await?;
+        if data.len() as u64 != size {
+            bail!("Upload Error: stream ended after {} of {} bytes", data.len(), size);
+        }
+        Ok(data)
+    }
+    fn check_size(&self, size: u64) -> Result<()> {
+        if size > self.max_size {
+            bail!("Upload Error: {} bytes exceeds limit of {}", size, self.max_size);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_receive() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let uploads = Uploads::new(dir.path(), 8);
+        let data = b"a jpeg!";
+        let path = uploads.receive(&mut &data[..], "a.jpg", 7).await?;
+        assert_eq!(std::fs::read(&path)?, data);
+
+        // never overwrite, never escape, never exceed the limit
+        assert!(uploads.receive(&mut &data[..], "a.jpg", 7).await.is_err());
+        assert!(uploads.receive(&mut &data[..], "../b.jpg", 7).await.is_err());
+        assert!(uploads.receive(&mut &b"too large"[..], "c.jpg", 9).await.is_err());
+
+        // a short upload leaves nothing behind
+        assert!(uploads.receive(&mut &data[..], "d.jpg", 8).await.is_err());
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let uploads = Uploads::new(dir.path(), 8);
+        assert_eq!(uploads.read(&mut &b"a jpeg!"[..], 7).await?, b"a jpeg!");
+        assert!(uploads.read(&mut &b"a jpeg!"[..], 8).await.is_err());
+        assert!(uploads.read(&mut &b"too large"[..], 9).await.is_err());
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 0);
+        Ok(())
+    }
+}
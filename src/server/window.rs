@@ -2,18 +2,26 @@ use anyhow::{anyhow, Result};
 use sdl2::EventPump;
 use sdl2::event::EventPollIterator;
 use sdl2::image::LoadTexture;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
 use sdl2::{render::WindowCanvas, video::FullscreenType};
 use std::path::Path;
 use std::ffi::OsString;
 
+/// Lit segments of the digits 0-9, bits 0 to 6 being the segments
+/// top, top right, bottom right, bottom, bottom left, top left, middle.
+const SEGMENTS: [u8; 10] = [0x3f, 0x06, 0x5b, 0x4f, 0x66, 0x6d, 0x7d, 0x07, 0x7f, 0x6f];
+
 pub struct Window {
     rotation: f64,
     fullscreen: FullscreenType,
-    pub pageant_mode: bool,
-    pub pageant_ready: bool,
     pub canvas: WindowCanvas,
     window_title: OsString,
-    event_pump: EventPump
+    event_pump: EventPump,
+    /// Pairing PIN drawn over the image
+    pin: Option<String>,
+    /// Encoded image shown instead of the current one while casting
+    cast: Option<Vec<u8>>,
 }
 
 impl Window {
@@ -24,8 +32,6 @@ impl Window {
             .map_err(|e| anyhow!("Navigator init Error: {}", e))?;
         let fullscreen = FullscreenType::Off;
         let rotation: f64 = 0.0;
-        let pageant_mode = false;
-        let pageant_ready = false;
         let video_subsystem = sdl_context
             .video()
             .map_err(|e| anyhow!("Navigator init Error: {}", e))?;
@@ -47,16 +53,16 @@ impl Window {
         let s = Self {
             fullscreen,
             rotation,
-            pageant_mode,
             canvas,
             window_title: title,
-            pageant_ready,
-	    event_pump
+	    event_pump,
+	    pin: None,
+	    cast: None,
         };
 
         Ok(s)
     }
-    pub fn poll_events(&mut self) -> EventPollIterator {
+    pub fn poll_events(&mut self) -> EventPollIterator<'_> {
 	self.event_pump.poll_iter()
     }
     /// wraps update methods
@@ -74,9 +80,12 @@ impl Window {
     pub fn update_canvas(&mut self, image: &Path) -> Result<()> {
         self.canvas.clear();
         let texture_creator = self.canvas.texture_creator();
-        let texture = texture_creator
-            .load_texture(image.clone())
-            .map_err(|e| anyhow!("Update Canvas Error: {}", e))?;
+        // a cast image is decoded from memory through an SDL RWops
+        let texture = match &self.cast {
+            Some(data) => texture_creator.load_texture_bytes(data),
+            None => texture_creator.load_texture(image),
+        }
+        .map_err(|e| anyhow!("Update Canvas Error: {}", e))?;
         self.canvas
             .copy_ex(
                 &texture,
@@ -88,9 +97,80 @@ impl Window {
                 false,
             )
             .map_err(|e| anyhow!("Update Canvas Error: {}", e))?;
+        if let Some(pin) = self.pin.clone() {
+            self.draw_pin(&pin)?;
+        }
         self.canvas.present();
         Ok(())
     }
+    /// Show the encoded image `data` over `image` until `end_cast`.
+    pub fn cast(&mut self, data: Vec<u8>, image: &Path) -> Result<()> {
+	let texture_creator = self.canvas.texture_creator();
+	texture_creator
+	    .load_texture_bytes(&data)
+	    .map_err(|e| anyhow!("Cast Error: not a supported image: {}", e))?;
+	self.cast = Some(data);
+	self.update_canvas(image)
+    }
+    /// Forget the cast image. Returns whether there was one; the
+    /// caller redraws.
+    pub fn end_cast(&mut self) -> bool {
+	self.cast.take().is_some()
+    }
+    pub fn is_casting(&self) -> bool {
+	self.cast.is_some()
+    }
+    pub fn pin(&self) -> Option<&str> {
+	self.pin.as_deref()
+    }
+    /// Show `pin` over the image, or remove it with `None`.
+    pub fn show_pin(&mut self, pin: Option<String>, image: &Path) -> Result<()> {
+	self.pin = pin;
+	self.update_canvas(image)
+    }
+    /// Draw `pin` in seven segment digits on a dark box in the middle
+    /// of the canvas. SDL has no text rendering without SDL_ttf.
+    fn draw_pin(&mut self, pin: &str) -> Result<()> {
+	let (width, height) = self.canvas
+	    .output_size()
+	    .map_err(|e| anyhow!("Draw PIN Error: {}", e))?;
+	let digits = pin.len() as i32;
+	let h = (height / 5).max(20) as i32;
+	let w = h / 2;
+	let t = (h / 10).max(2);
+	let gap = w / 2;
+	let total = digits * w + (digits - 1) * gap;
+	let (x0, y0) = ((width as i32 - total) / 2, (height as i32 - h) / 2);
+	let rect = |x, y, w, h| Rect::new(x, y, w as u32, h as u32);
+
+	self.canvas.set_draw_color(Color::BLACK);
+	self.canvas
+	    .fill_rect(rect(x0 - gap, y0 - gap, total + 2 * gap, h + 2 * gap))
+	    .map_err(|e| anyhow!("Draw PIN Error: {}", e))?;
+	self.canvas.set_draw_color(Color::WHITE);
+	for (i, digit) in pin.chars().filter_map(|c| c.to_digit(10)).enumerate() {
+	    let x = x0 + i as i32 * (w + gap);
+	    let segments = [
+		rect(x, y0, w, t),
+		rect(x + w - t, y0, t, h / 2),
+		rect(x + w - t, y0 + h / 2, t, h / 2),
+		rect(x, y0 + h - t, w, t),
+		rect(x, y0 + h / 2, t, h / 2),
+		rect(x, y0, t, h / 2),
+		rect(x, y0 + (h - t) / 2, w, t),
+	    ];
+	    for (bit, segment) in segments.iter().enumerate() {
+		if SEGMENTS[digit as usize] & (1 << bit) != 0 {
+		    self.canvas
+			.fill_rect(*segment)
+			.map_err(|e| anyhow!("Draw PIN Error: {}", e))?;
+		}
+	    }
+	}
+	// clear() paints with the draw color
+	self.canvas.set_draw_color(Color::BLACK);
+	Ok(())
+    }
     pub fn update_window(&mut self) -> Result<()> {
         let window = self.canvas.window_mut();
         window.set_fullscreen(self.fullscreen).unwrap();
@@ -113,14 +193,18 @@ impl Window {
         self.update_canvas(image)?;
         Ok(())
     }
-    pub fn pageant_toggle(&mut self) {
-        self.pageant_mode = !self.pageant_mode;
-    }
     pub fn rotate(&mut self, f: f64, image: &Path) -> Result<()> {
         self.rotation += f;
         self.update_canvas(image)?;
         Ok(())
     }
+    /// current rotation in degrees
+    pub fn rotation(&self) -> f64 {
+        self.rotation * 90_f64
+    }
+    pub fn is_fullscreen(&self) -> bool {
+        self.fullscreen != FullscreenType::Off
+    }
     pub fn try_load(&mut self, image: &Path) -> Option<()> {
         let texture_creator = self.canvas.texture_creator();
         texture_creator.load_texture(image).ok().map(|_| ())
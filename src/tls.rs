@@ -0,0 +1,278 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{
+    ClientConfig, DigitallySignedStruct, DistinguishedName, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::model::ALPN;
+
+/// File name of the generated server certificate
+pub const CERT_FILE: &str = "cert.pem";
+/// File name of the generated server private key
+pub const KEY_FILE: &str = "key.pem";
+
+/// Directory holding viewd's keys and related state,
+/// e.g. `~/.config/viewd`.
+pub fn config_dir() -> Result<PathBuf> {
+    dirs::config_dir()
+        .map(|dir| dir.join("viewd"))
+        .ok_or(anyhow!("TLS Error: no configuration directory for this user"))
+}
+
+/// A certificate and its private key, PEM encoded.
+pub struct Identity {
+    pub cert: String,
+    pub key: String,
+}
+
+impl Identity {
+    /// Read a certificate and key from the given files.
+    pub fn load(cert: &Path, key: &Path) -> Result<Self> {
+        let cert = read_pem(cert)?;
+        let key = read_pem(key)?;
+        Ok(Self { cert, key })
+    }
+    /// Load the identity stored in `dir`, generating a self-signed one
+    /// on first run. Either file without the other is an error, rather
+    /// than a reason to overwrite it.
+    pub fn load_or_generate(dir: &Path) -> Result<Self> {
+        let cert = dir.join(CERT_FILE);
+        let key = dir.join(KEY_FILE);
+        match (cert.exists(), key.exists()) {
+            (true, true) => {}
+            (false, false) => {
+                Self::generate_self_signed()?.save(&cert, &key)?;
+                info!("generated self-signed certificate {}", cert.display());
+            }
+            (true, false) => bail!(
+                "TLS Error: {} has no key {}, restore it or move the certificate away",
+                cert.display(),
+                key.display()
+            ),
+            (false, true) => bail!(
+                "TLS Error: {} has no certificate {}, restore it or move the key away",
+                key.display(),
+                cert.display()
+            ),
+        }
+        Self::load(&cert, &key)
+    }
+    /// Create a certificate for `localhost` and this machine's host name.
+    pub fn generate_self_signed() -> Result<Self> {
+        let mut names = vec!["localhost".to_string()];
+        if let Some(host) = hostname::get().ok().and_then(|h| h.into_string().ok()) {
+            names.push(host);
+        }
+        Self::generate_for(names)
+    }
+    /// Create a self-signed certificate for the given names.
+    pub fn generate_for(names: Vec<String>) -> Result<Self> {
+        let certified = rcgen::generate_simple_self_signed(names)
+            .map_err(|e| anyhow!("TLS Error: generate certificate: {}", e))?;
+        let cert = certified.cert.pem();
+        let key = certified.key_pair.serialize_pem();
+        Ok(Self { cert, key })
+    }
+    /// Certificate chain and private key as rustls takes them
+    fn parse(&self) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+        let certs = parse_certificates(&self.cert)?;
+        let key = PrivateKeyDer::from_pem_slice(self.key.as_bytes())
+            .map_err(|e| anyhow!("TLS Error: parse private key: {}", e))?;
+        Ok((certs, key))
+    }
+    /// Fingerprint of the leaf certificate, as clients pin it.
+    pub fn fingerprint(&self) -> Result<String> {
+        Ok(fingerprint(&parse_certificates(&self.cert)?[0]))
+    }
+    /// Write certificate and key, the key readable only by its owner.
+    pub fn save(&self, cert: &Path, key: &Path) -> Result<()> {
+        if let Some(dir) = cert.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(cert, &self.cert)?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(key)
+            .map_err(|e| anyhow!("TLS Error: create {}: {}", key.display(), e))?;
+        file.write_all(self.key.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// SHA-256 of a DER certificate as colon separated hex, the form
+/// fingerprints are shown and stored in.
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Crypto used by every TLS endpoint. QUIC only runs over TLS 1.3,
+/// whose cipher suites all work with s2n-quic.
+pub fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::aws_lc_rs::default_provider())
+}
+
+/// Parse every certificate in a PEM string.
+pub fn parse_certificates(pem: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_slice_iter(pem.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("TLS Error: parse certificate: {}", e))?;
+    if certs.is_empty() {
+        return Err(anyhow!("TLS Error: no certificate found"));
+    }
+    Ok(certs)
+}
+
+/// Verifier accepting servers whose chain leads to the PEM `ca`.
+pub fn ca_verifier(ca: &str) -> Result<Arc<dyn ServerCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in parse_certificates(ca)? {
+        roots.add(cert)?;
+    }
+    let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider())
+        .build()
+        .map_err(|e| anyhow!("TLS Error: {}", e))?;
+    Ok(verifier)
+}
+
+/// TLS client config that trusts whichever servers `verifier` accepts,
+/// authenticating with `identity` if given.
+pub fn client_config(
+    verifier: Arc<dyn ServerCertVerifier>,
+    identity: Option<&Identity>,
+) -> Result<ClientConfig> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier);
+    let mut config = match identity {
+        Some(identity) => {
+            let (certs, key) = identity.parse()?;
+            builder.with_client_auth_cert(certs, key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = vec![ALPN.to_vec()];
+    Ok(config)
+}
+
+/// TLS server config presenting `identity`. With `client_auth` clients
+/// are asked for a certificate, which is left for the caller to judge.
+pub fn server_config(identity: &Identity, client_auth: bool) -> Result<ServerConfig> {
+    let (certs, key) = identity.parse()?;
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])?;
+    let builder = if client_auth {
+        builder.with_client_cert_verifier(Arc::new(AnyClientCert { provider: provider() }))
+    } else {
+        builder.with_no_client_auth()
+    };
+    let mut config = builder.with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![ALPN.to_vec()];
+    Ok(config)
+}
+
+/// Accepts any client certificate, or none, as long as the client
+/// proves it holds the key. Clients are authorized by fingerprint once
+/// the connection is up, where the refusal can be explained to them.
+#[derive(Debug)]
+struct AnyClientCert {
+    provider: Arc<CryptoProvider>,
+}
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Read a PEM file, naming it in the error.
+pub fn read_pem(path: &Path) -> Result<String> {
+    fs::read_to_string(path).map_err(|e| anyhow!("TLS Error: read {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_generate_once() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let first = Identity::load_or_generate(dir.path())?;
+        assert!(first.cert.starts_with("-----BEGIN CERTIFICATE-----"));
+        let mode = fs::metadata(dir.path().join(KEY_FILE))?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // later runs reuse the stored identity
+        let second = Identity::load_or_generate(dir.path())?;
+        assert_eq!(first.cert, second.cert);
+
+        // half an identity is left as it is
+        fs::remove_file(dir.path().join(CERT_FILE))?;
+        assert!(Identity::load_or_generate(dir.path()).is_err());
+        assert!(!dir.path().join(CERT_FILE).exists());
+        assert_eq!(fs::read_to_string(dir.path().join(KEY_FILE))?, first.key);
+        fs::rename(dir.path().join(KEY_FILE), dir.path().join(CERT_FILE))?;
+        assert!(Identity::load_or_generate(dir.path()).is_err());
+        assert!(!dir.path().join(KEY_FILE).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let fp = fingerprint(b"");
+        assert!(fp.starts_with("e3:b0:c4:42:98:fc"));
+        assert_eq!(fp.len(), 32 * 3 - 1);
+    }
+}
@@ -0,0 +1,141 @@
+//! Turning the addresses given on the command line into socket
+//! addresses. A host is a name or an IP literal, IPv6 ones in
+//! brackets when followed by a port, such as `frame.local:4433`,
+//! `[fe80::1]:4433` or `192.168.1.20`.
+
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+
+use crate::transport::{Connection, Peer, ServerTransport};
+
+/// Port servers listen on unless told otherwise
+pub const DEFAULT_PORT: u16 = 4433;
+
+/// The host and port of `address`, the port defaulting to `DEFAULT_PORT`
+pub fn split(address: &str) -> Result<(&str, u16)> {
+    // a bare IPv6 literal is all colons
+    if address.parse::<IpAddr>().is_ok() {
+        return Ok((address, DEFAULT_PORT));
+    }
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) if !address.ends_with(']') => {
+            let port = port
+                .parse()
+                .map_err(|e| anyhow!("Address Error: bad port in {}: {}", address, e))?;
+            (host, port)
+        }
+        _ => (address, DEFAULT_PORT),
+    };
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() {
+        return Err(anyhow!("Address Error: no host in {}", address));
+    }
+    Ok((host, port))
+}
+
+/// The name `address` is reached by, to check the server certificate
+/// against. None for an IP literal.
+pub fn host_name(address: &str) -> Option<&str> {
+    let (host, _) = split(address).ok()?;
+    host.parse::<IpAddr>().is_err().then_some(host)
+}
+
+/// Every address `address` stands for, looking names up
+pub async fn resolve(address: &str) -> Result<Vec<SocketAddr>> {
+    let (host, port) = split(address)?;
+    let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+    if addresses.is_empty() {
+        return Err(anyhow!("Address Error: {} has no addresses", host));
+    }
+    Ok(addresses)
+}
+
+/// Every address the server is to listen on, each once. Names are
+/// looked up so that `localhost` binds both loopback addresses.
+pub fn resolve_binds(binds: &[String]) -> Result<Vec<SocketAddr>> {
+    let mut seen = HashSet::new();
+    let mut addresses = Vec::new();
+    for bind in binds {
+        let (host, port) = split(bind)?;
+        let resolved = (host, port)
+            .to_socket_addrs()
+            .map_err(|e| anyhow!("Address Error: {}: {}", bind, e))?;
+        addresses.extend(resolved.filter(|a| seen.insert(*a)));
+    }
+    if addresses.is_empty() {
+        return Err(anyhow!("Address Error: nothing to listen on"));
+    }
+    Ok(addresses)
+}
+
+/// Clients arriving on any of several transports
+struct Merged {
+    incoming: mpsc::UnboundedReceiver<(Connection, Peer)>,
+}
+
+/// One transport taking in the clients of all of `transports`
+pub fn merge(mut transports: Vec<Box<dyn ServerTransport>>) -> Box<dyn ServerTransport> {
+    if transports.len() == 1 {
+        return transports.remove(0);
+    }
+    let (tx, incoming) = mpsc::unbounded_channel();
+    for mut transport in transports {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            while let Some(client) = transport.accept().await {
+                if tx.send(client).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    Box::new(Merged { incoming })
+}
+
+#[async_trait]
+impl ServerTransport for Merged {
+    async fn accept(&mut self) -> Option<(Connection, Peer)> {
+        self.incoming.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() -> Result<()> {
+        assert_eq!(split("frame.local:4500")?, ("frame.local", 4500));
+        assert_eq!(split("frame.local")?, ("frame.local", DEFAULT_PORT));
+        assert_eq!(split("192.168.1.20")?, ("192.168.1.20", DEFAULT_PORT));
+        assert_eq!(split("[fe80::1]:4500")?, ("fe80::1", 4500));
+        assert_eq!(split("[::1]")?, ("::1", DEFAULT_PORT));
+        assert_eq!(split("::1")?, ("::1", DEFAULT_PORT));
+        assert!(split("frame.local:http").is_err());
+        assert!(split(":4433").is_err());
+
+        assert_eq!(host_name("frame.local:4433"), Some("frame.local"));
+        assert_eq!(host_name("[::1]:4433"), None);
+        assert_eq!(host_name("127.0.0.1"), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve() -> Result<()> {
+        let addresses = resolve("localhost:4500").await?;
+        assert!(addresses.iter().all(|a| a.ip().is_loopback() && a.port() == 4500));
+        assert_eq!(resolve("[::1]").await?, vec!["[::1]:4433".parse()?]);
+
+        let binds = ["127.0.0.1:4500".to_string(), "127.0.0.1:4500".to_string(), "[::]:4500".to_string()];
+        let addresses = resolve_binds(&binds)?;
+        assert_eq!(addresses, vec!["127.0.0.1:4500".parse()?, "[::]:4500".parse()?]);
+        Ok(())
+    }
+}
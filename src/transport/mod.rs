@@ -0,0 +1,251 @@
+//! How clients and the server reach each other. Whatever the transport,
+//! a connection carries the same streams: the first one a client opens
+//! holds the handshake and its requests, the server opens more for
+//! events and fetched files. QUIC has streams of its own, TCP and Unix
+//! sockets are given them by `mux`. QUIC connections also carry
+//! datagrams, for messages worth less than their delay.
+
+pub mod address;
+pub mod mux;
+
+use std::fmt;
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use s2n_quic::{
+    application,
+    connection::{Handle, StreamAcceptor},
+    provider::datagram::default::{Endpoint, Receiver, Sender},
+    stream::ReceiveStream,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Receiving half of a stream
+pub trait Receive: AsyncRead + Send + Unpin {
+    /// Tell the peer the rest of the stream isn't wanted
+    fn stop_sending(&mut self);
+}
+
+pub type Reader = Box<dyn Receive>;
+pub type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Datagrams either side keeps queued before dropping the oldest
+const DATAGRAM_QUEUE: usize = 16;
+
+/// Pause after a failed accept before the next, so running out of file
+/// descriptors doesn't spin. Listeners keep going, the failure is
+/// usually over by then.
+pub const ACCEPT_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// A stream both sides send on
+pub struct Stream {
+    reader: Reader,
+    writer: Writer,
+}
+
+impl Stream {
+    pub fn new(reader: Reader, writer: Writer) -> Self {
+        Self { reader, writer }
+    }
+    pub fn split(self) -> (Reader, Writer) {
+        (self.reader, self.writer)
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
+    }
+}
+
+/// Opens streams on a connection, shared by the tasks using it
+#[async_trait]
+pub trait Opener: Send + Sync {
+    async fn open_send(&self) -> Result<Writer>;
+    async fn open_bidirectional(&self) -> Result<Stream>;
+}
+
+/// Takes in the streams the peer opens. Both return None once the
+/// connection is closed.
+#[async_trait]
+pub trait Acceptor: Send {
+    async fn accept_bidirectional(&mut self) -> Result<Option<Stream>>;
+    async fn accept_receive(&mut self) -> Result<Option<Reader>>;
+}
+
+/// Datagrams a connection carries beside its streams. They may be
+/// lost or arrive out of order, and the oldest queued are dropped when
+/// either side falls behind.
+#[async_trait]
+pub trait Datagrams: Send + Sync {
+    /// Queue `data` to be sent to the peer
+    fn send(&self, data: Bytes) -> Result<()>;
+    /// Wait for the next datagram. None once the connection is closed.
+    async fn receive(&self) -> Option<Bytes>;
+    /// A datagram that has already arrived, if any
+    fn try_receive(&self) -> Option<Bytes>;
+}
+
+/// A connection, open for as long as any part of it is kept
+pub struct Connection {
+    pub opener: Arc<dyn Opener>,
+    pub acceptor: Box<dyn Acceptor>,
+    /// None when the transport has no datagrams
+    pub datagrams: Option<Arc<dyn Datagrams>>,
+}
+
+/// Where a client connected from and how it identified itself
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub address: String,
+    /// Fingerprint of the certificate the client presented, if any
+    pub fingerprint: Option<String>,
+    /// Came in over a local socket, which its file permissions guard
+    pub local: bool,
+}
+
+/// Server side of a transport
+#[async_trait]
+pub trait ServerTransport: Send {
+    /// Wait for the next client. None once no more can arrive.
+    async fn accept(&mut self) -> Option<(Connection, Peer)>;
+}
+
+/// Client side of a transport
+#[async_trait]
+pub trait ClientTransport: Send {
+    /// Open a new connection to the server
+    async fn connect(&mut self) -> Result<Connection>;
+}
+
+/// Transports to choose from on the command line
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransportKind {
+    /// QUIC over UDP
+    #[default]
+    Quic,
+    /// TLS over TCP, for networks that block UDP
+    Tcp,
+    /// A Unix socket on this machine, without TLS
+    Unix,
+}
+
+impl FromStr for TransportKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "quic" => Ok(Self::Quic),
+            "tcp" => Ok(Self::Tcp),
+            "unix" => Ok(Self::Unix),
+            _ => bail!("unknown transport {}, expected quic, tcp or unix", s),
+        }
+    }
+}
+
+impl fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Quic => write!(f, "quic"),
+            Self::Tcp => write!(f, "tcp"),
+            Self::Unix => write!(f, "unix"),
+        }
+    }
+}
+
+impl Connection {
+    /// Streams of a QUIC connection
+    pub fn quic(connection: s2n_quic::Connection) -> Connection {
+        let (handle, acceptor) = connection.split();
+        Connection {
+            opener: Arc::new(handle.clone()),
+            acceptor: Box::new(acceptor),
+            datagrams: Some(Arc::new(handle)),
+        }
+    }
+}
+
+/// Datagram queues of a QUIC endpoint, clients and server alike
+pub fn datagram_endpoint() -> Result<Endpoint> {
+    let endpoint = Endpoint::builder()
+        .with_send_capacity(DATAGRAM_QUEUE)
+        .and_then(|builder| builder.with_recv_capacity(DATAGRAM_QUEUE))
+        .map_err(|e| anyhow!("Transport Error: datagrams: {}", e))?
+        .build()?;
+    Ok(endpoint)
+}
+
+impl Receive for ReceiveStream {
+    fn stop_sending(&mut self) {
+        ReceiveStream::stop_sending(self, application::Error::UNKNOWN).ok();
+    }
+}
+
+#[async_trait]
+impl Opener for Handle {
+    async fn open_send(&self) -> Result<Writer> {
+        Ok(Box::new(self.clone().open_send_stream().await?))
+    }
+    async fn open_bidirectional(&self) -> Result<Stream> {
+        let (receive, send) = self.clone().open_bidirectional_stream().await?.split();
+        Ok(Stream::new(Box::new(receive), Box::new(send)))
+    }
+}
+
+#[async_trait]
+impl Datagrams for Handle {
+    fn send(&self, data: Bytes) -> Result<()> {
+        // a full queue pushes out the oldest datagram, it was stale anyway
+        self.datagram_mut(|sender: &mut Sender| sender.send_datagram_forced(data))?
+            .map_err(|e| anyhow!("Transport Error: datagram: {}", e))?;
+        Ok(())
+    }
+    async fn receive(&self) -> Option<Bytes> {
+        poll_fn(|cx| {
+            match self.datagram_mut(|receiver: &mut Receiver| receiver.poll_recv_datagram(cx)) {
+                Ok(Poll::Ready(Ok(data))) => Poll::Ready(Some(data)),
+                Ok(Poll::Pending) => Poll::Pending,
+                _ => Poll::Ready(None),
+            }
+        })
+        .await
+    }
+    fn try_receive(&self) -> Option<Bytes> {
+        self.datagram_mut(|receiver: &mut Receiver| receiver.recv_datagram())
+            .ok()
+            .flatten()
+    }
+}
+
+#[async_trait]
+impl Acceptor for StreamAcceptor {
+    async fn accept_bidirectional(&mut self) -> Result<Option<Stream>> {
+        let stream = self.accept_bidirectional_stream().await?;
+        Ok(stream.map(|stream| {
+            let (receive, send) = stream.split();
+            Stream::new(Box::new(receive), Box::new(send))
+        }))
+    }
+    async fn accept_receive(&mut self) -> Result<Option<Reader>> {
+        let stream = self.accept_receive_stream().await?;
+        Ok(stream.map(|stream| Box::new(stream) as Reader))
+    }
+}
@@ -0,0 +1,637 @@
+//! Streams over a single ordered byte stream, for transports without
+//! streams of their own. Every frame names the stream it belongs to:
+//!
+//!     stream id u32 | kind u8 | payload length u32 | payload
+//!
+//! Ids of streams the dialing side opens are even, the listening side's
+//! are odd, and each side's only ever grow. A stream is opened by its
+//! first frame, ended by `FIN` and abandoned by `RESET`; `STOP` asks
+//! the other side to stop sending. Only the listening side opens send
+//! streams. A side may send `STREAM_WINDOW` bytes on a stream before it
+//! waits for the reader to make room with `WINDOW`, whose payload is
+//! the u32 number of bytes read since. A peer breaking these rules has
+//! its connection closed.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{ready, Context, Poll};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::PollSemaphore;
+use tracing::debug;
+
+use crate::transport::{Acceptor, Connection, Opener, Reader, Receive, Stream, Writer};
+
+const OPEN_BIDI: u8 = 0;
+const OPEN_UNI: u8 = 1;
+const DATA: u8 = 2;
+const FIN: u8 = 3;
+const RESET: u8 = 4;
+const STOP: u8 = 5;
+const WINDOW: u8 = 6;
+
+/// Largest payload of a data frame
+const MAX_PAYLOAD: usize = 64 * 1024;
+/// Bytes waiting to be written, across all streams, before writers wait
+const SEND_WINDOW: usize = 1024 * 1024;
+/// Bytes sent on a stream and not yet read before its writer waits, so
+/// a slow reader doesn't hold up the other streams
+const STREAM_WINDOW: usize = 16 * MAX_PAYLOAD;
+
+/// Which end of the byte stream this is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Dialer,
+    Listener,
+}
+
+/// Run streams over `io`.
+pub fn connection<S>(io: S, side: Side) -> Connection
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (read, write) = tokio::io::split(io);
+    let (outbound, frames) = mpsc::unbounded_channel();
+    let shared = Arc::new(Shared {
+        streams: Mutex::new(HashMap::new()),
+        next_id: AtomicU32::new(if side == Side::Dialer { 0 } else { 1 }),
+        outbound,
+        window: Arc::new(Semaphore::new(SEND_WINDOW)),
+        closed: AtomicBool::new(false),
+    });
+    let (bidi, incoming_bidi) = mpsc::unbounded_channel();
+    let (uni, incoming_uni) = mpsc::unbounded_channel();
+    let (sent_all, stop) = oneshot::channel();
+    let window = shared.window.clone();
+    tokio::spawn(async move {
+        if let Err(e) = send(BufWriter::new(write), frames, window).await {
+            debug!("connection write failed: {}", e);
+        }
+        sent_all.send(()).ok();
+    });
+    let weak = Arc::downgrade(&shared);
+    tokio::spawn(async move {
+        let result = tokio::select! {
+            result = receive(read, side, weak.clone(), bidi, uni) => result,
+            _ = stop => Ok(()),
+        };
+        if let Err(e) = result {
+            debug!("connection read failed: {}", e);
+        }
+        // whatever is left unread won't be finished, nor written
+        if let Some(shared) = weak.upgrade() {
+            shared.closed.store(true, Ordering::Relaxed);
+            let mut streams = shared.streams.lock().unwrap();
+            for entry in streams.values() {
+                entry.window.close();
+            }
+            streams.clear();
+        }
+    });
+    Connection {
+        opener: Arc::new(MuxOpener(shared.clone())),
+        acceptor: Box::new(MuxAcceptor {
+            bidi: incoming_bidi,
+            uni: incoming_uni,
+            _shared: shared,
+        }),
+        // a byte stream has no room for messages that may get lost
+        datagrams: None,
+    }
+}
+
+struct Frame {
+    id: u32,
+    kind: u8,
+    payload: Bytes,
+}
+
+/// State of a stream the connection still routes frames for
+struct Entry {
+    // where received data goes, until the stream is finished or stopped
+    inbound: Option<mpsc::UnboundedSender<Option<Bytes>>>,
+    // bytes the peer may still send before the reader makes room
+    credit: usize,
+    // whether the peer asked us to stop sending
+    stopped: Arc<AtomicBool>,
+    // bytes we may still send, given back as the peer reads them
+    window: Arc<Semaphore>,
+    writing: bool,
+}
+
+/// Everything the parts of a connection share. The connection lasts as
+/// long as this does.
+struct Shared {
+    streams: Mutex<HashMap<u32, Entry>>,
+    next_id: AtomicU32,
+    outbound: mpsc::UnboundedSender<Frame>,
+    window: Arc<Semaphore>,
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn queue(&self, id: u32, kind: u8, payload: Bytes) -> std::io::Result<()> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(lost());
+        }
+        self.outbound
+            .send(Frame { id, kind, payload })
+            .map_err(|_| lost())
+    }
+    /// Register a stream and return its reader, if data is to be
+    /// received on it, and writer, if sent.
+    fn add(self: &Arc<Self>, id: u32, read: bool, write: bool) -> (Option<MuxReader>, Option<MuxWriter>) {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let window = Arc::new(Semaphore::new(STREAM_WINDOW));
+        let (inbound, reader) = match read {
+            true => {
+                let (tx, rx) = mpsc::unbounded_channel();
+                (Some(tx), Some(MuxReader::new(id, rx, self.clone())))
+            }
+            false => (None, None),
+        };
+        let writer = write.then(|| MuxWriter::new(id, stopped.clone(), window.clone(), self.clone()));
+        let entry = Entry {
+            inbound,
+            credit: STREAM_WINDOW,
+            stopped,
+            window,
+            writing: write,
+        };
+        self.streams.lock().unwrap().insert(id, entry);
+        (reader, writer)
+    }
+    /// Let the peer send `read` more bytes on `id`, now they are read
+    fn make_room(&self, id: u32, read: usize) {
+        if let Some(entry) = self.streams.lock().unwrap().get_mut(&id) {
+            entry.credit += read;
+        }
+        self.queue(id, WINDOW, Bytes::copy_from_slice(&(read as u32).to_be_bytes())).ok();
+    }
+    /// Stop routing frames to the reader of `id`
+    fn end_reading(&self, id: u32) {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(entry) = streams.get_mut(&id) {
+            entry.inbound = None;
+            if !entry.writing {
+                streams.remove(&id);
+            }
+        }
+    }
+    /// Forget the writer of `id`
+    fn end_writing(&self, id: u32) {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(entry) = streams.get_mut(&id) {
+            entry.writing = false;
+            if entry.inbound.is_none() {
+                streams.remove(&id);
+            }
+        }
+    }
+    fn open_id(&self) -> u32 {
+        self.next_id.fetch_add(2, Ordering::Relaxed)
+    }
+}
+
+fn lost() -> Error {
+    Error::new(ErrorKind::ConnectionReset, "Mux Error: connection lost")
+}
+
+/// Write queued frames to `io` until every part of the connection is
+/// gone.
+async fn send<W>(mut io: W, mut frames: mpsc::UnboundedReceiver<Frame>, window: Arc<Semaphore>) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(frame) = frames.recv().await {
+        let mut header = [0u8; 9];
+        header[..4].copy_from_slice(&frame.id.to_be_bytes());
+        header[4] = frame.kind;
+        header[5..].copy_from_slice(&(frame.payload.len() as u32).to_be_bytes());
+        io.write_all(&header).await?;
+        io.write_all(&frame.payload).await?;
+        if frame.kind == DATA {
+            window.add_permits(frame.payload.len());
+        }
+        if frames.is_empty() {
+            io.flush().await?;
+        }
+    }
+    io.shutdown().await?;
+    Ok(())
+}
+
+/// Route frames read from `io` to their streams until the peer closes
+/// the connection. Never waits for a stream's reader, the window each
+/// stream has keeps what piles up for it bounded.
+async fn receive<R>(
+    mut io: R,
+    side: Side,
+    shared: Weak<Shared>,
+    bidi: mpsc::UnboundedSender<Stream>,
+    uni: mpsc::UnboundedSender<Reader>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    // the id the peer's next stream may have, at the least
+    let mut next_open: u64 = if side == Side::Dialer { 1 } else { 0 };
+    loop {
+        let mut header = [0u8; 9];
+        match io.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let id = u32::from_be_bytes(header[..4].try_into()?);
+        let len = u32::from_be_bytes(header[5..].try_into()?) as usize;
+        if len > MAX_PAYLOAD {
+            bail!("Mux Error: {} byte frame exceeds limit of {}", len, MAX_PAYLOAD);
+        }
+        let mut payload = vec![0; len];
+        io.read_exact(&mut payload).await?;
+        let Some(shared) = shared.upgrade() else {
+            return Ok(());
+        };
+        if matches!(header[4], OPEN_BIDI | OPEN_UNI) {
+            // taking over one of ours, or one of its own still open
+            if u64::from(id) % 2 != next_open % 2 || u64::from(id) < next_open {
+                bail!("Mux Error: peer opened stream {} out of turn", id);
+            }
+            next_open = u64::from(id) + 2;
+        }
+        match header[4] {
+            // nobody would accept it, it would only pile up
+            OPEN_UNI if side == Side::Listener => {
+                bail!("Mux Error: the dialing side opened a send stream");
+            }
+            OPEN_BIDI => {
+                if let (Some(reader), Some(writer)) = shared.add(id, true, true) {
+                    bidi.send(Stream::new(Box::new(reader), Box::new(writer))).ok();
+                }
+            }
+            OPEN_UNI => {
+                if let (Some(reader), _) = shared.add(id, true, false) {
+                    uni.send(Box::new(reader)).ok();
+                }
+            }
+            DATA => {
+                let mut streams = shared.streams.lock().unwrap();
+                // frames for a reader that went away are dropped
+                if let Some(entry) = streams.get_mut(&id).filter(|e| e.inbound.is_some()) {
+                    if len > entry.credit {
+                        bail!("Mux Error: stream {} overran its window", id);
+                    }
+                    entry.credit -= len;
+                    if let Some(inbound) = &entry.inbound {
+                        inbound.send(Some(Bytes::from(payload))).ok();
+                    }
+                }
+            }
+            FIN => {
+                let inbound = shared.streams.lock().unwrap().get(&id).and_then(|e| e.inbound.clone());
+                if let Some(inbound) = inbound {
+                    inbound.send(None).ok();
+                }
+                shared.end_reading(id);
+            }
+            RESET => shared.end_reading(id),
+            STOP => {
+                if let Some(entry) = shared.streams.lock().unwrap().get(&id) {
+                    entry.stopped.store(true, Ordering::Relaxed);
+                    // a writer waiting for room would wait forever
+                    entry.window.close();
+                }
+            }
+            WINDOW => {
+                let Ok(read) = <[u8; 4]>::try_from(payload.as_slice()).map(u32::from_be_bytes) else {
+                    bail!("Mux Error: bad window frame for stream {}", id);
+                };
+                if let Some(entry) = shared.streams.lock().unwrap().get(&id) {
+                    if entry.window.available_permits() + read as usize > STREAM_WINDOW {
+                        bail!("Mux Error: stream {} was given more room than it has", id);
+                    }
+                    entry.window.add_permits(read as usize);
+                }
+            }
+            kind => bail!("Mux Error: unknown frame kind {}", kind),
+        }
+    }
+}
+
+struct MuxOpener(Arc<Shared>);
+
+#[async_trait]
+impl Opener for MuxOpener {
+    async fn open_send(&self) -> Result<Writer> {
+        let id = self.0.open_id();
+        let (_, writer) = self.0.add(id, false, true);
+        self.0.queue(id, OPEN_UNI, Bytes::new())?;
+        Ok(Box::new(writer.expect("writer of a send stream")))
+    }
+    async fn open_bidirectional(&self) -> Result<Stream> {
+        let id = self.0.open_id();
+        let (reader, writer) = self.0.add(id, true, true);
+        self.0.queue(id, OPEN_BIDI, Bytes::new())?;
+        match (reader, writer) {
+            (Some(reader), Some(writer)) => Ok(Stream::new(Box::new(reader), Box::new(writer))),
+            _ => unreachable!("both halves of a bidirectional stream"),
+        }
+    }
+}
+
+struct MuxAcceptor {
+    bidi: mpsc::UnboundedReceiver<Stream>,
+    uni: mpsc::UnboundedReceiver<Reader>,
+    _shared: Arc<Shared>,
+}
+
+#[async_trait]
+impl Acceptor for MuxAcceptor {
+    async fn accept_bidirectional(&mut self) -> Result<Option<Stream>> {
+        Ok(self.bidi.recv().await)
+    }
+    async fn accept_receive(&mut self) -> Result<Option<Reader>> {
+        Ok(self.uni.recv().await)
+    }
+}
+
+struct MuxReader {
+    id: u32,
+    // None once the peer finished the stream
+    inbound: mpsc::UnboundedReceiver<Option<Bytes>>,
+    buffer: Bytes,
+    // bytes read since the peer was last given room for more
+    unacknowledged: usize,
+    finished: bool,
+    shared: Arc<Shared>,
+}
+
+impl MuxReader {
+    fn new(id: u32, inbound: mpsc::UnboundedReceiver<Option<Bytes>>, shared: Arc<Shared>) -> Self {
+        Self {
+            id,
+            inbound,
+            buffer: Bytes::new(),
+            unacknowledged: 0,
+            finished: false,
+            shared,
+        }
+    }
+}
+
+impl AsyncRead for MuxReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        loop {
+            if !self.buffer.is_empty() {
+                let n = self.buffer.len().min(buf.remaining());
+                buf.put_slice(&self.buffer.split_to(n));
+                // in halves, so the writer rarely has to wait
+                self.unacknowledged += n;
+                if self.unacknowledged >= STREAM_WINDOW / 2 {
+                    self.shared.make_room(self.id, self.unacknowledged);
+                    self.unacknowledged = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if self.finished {
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(self.inbound.poll_recv(cx)) {
+                Some(Some(data)) => self.buffer = data,
+                Some(None) => self.finished = true,
+                None => return Poll::Ready(Err(lost())),
+            }
+        }
+    }
+}
+
+impl Receive for MuxReader {
+    fn stop_sending(&mut self) {
+        if !self.finished {
+            self.finished = true;
+            self.buffer.clear();
+            self.shared.end_reading(self.id);
+            self.shared.queue(self.id, STOP, Bytes::new()).ok();
+        }
+    }
+}
+
+impl Drop for MuxReader {
+    fn drop(&mut self) {
+        self.stop_sending();
+    }
+}
+
+struct MuxWriter {
+    id: u32,
+    stopped: Arc<AtomicBool>,
+    // room the peer made on this stream
+    room: PollSemaphore,
+    // room taken on this stream while waiting for the connection's
+    taken: Option<OwnedSemaphorePermit>,
+    window: PollSemaphore,
+    finished: bool,
+    shared: Arc<Shared>,
+}
+
+impl MuxWriter {
+    fn new(id: u32, stopped: Arc<AtomicBool>, room: Arc<Semaphore>, shared: Arc<Shared>) -> Self {
+        Self {
+            id,
+            stopped,
+            room: PollSemaphore::new(room),
+            taken: None,
+            window: PollSemaphore::new(shared.window.clone()),
+            finished: false,
+            shared,
+        }
+    }
+    fn stopped_or_lost(&self) -> Error {
+        match self.stopped.load(Ordering::Relaxed) {
+            true => Error::new(ErrorKind::BrokenPipe, "Mux Error: peer stopped reading"),
+            false => lost(),
+        }
+    }
+}
+
+impl AsyncWrite for MuxWriter {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        if self.stopped.load(Ordering::Relaxed) {
+            return Poll::Ready(Err(Error::new(ErrorKind::BrokenPipe, "Mux Error: peer stopped reading")));
+        }
+        let n = buf.len().min(MAX_PAYLOAD);
+        if n == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        if self.taken.is_none() {
+            let room = ready!(self.room.poll_acquire_many(cx, n as u32)).ok_or_else(|| self.stopped_or_lost())?;
+            self.taken = Some(room);
+        }
+        let n = n.min(self.taken.as_ref().map_or(0, |room| room.num_permits()));
+        let permit = ready!(self.window.poll_acquire_many(cx, n as u32)).ok_or_else(lost)?;
+        // given back once the frame is written out
+        permit.forget();
+        // and on this stream once the peer reads it, what's left over
+        // goes back right away
+        if let Some(sent) = self.taken.take().and_then(|mut room| room.split(n)) {
+            sent.forget();
+        }
+        self.shared.queue(self.id, DATA, Bytes::copy_from_slice(&buf[..n]))?;
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if !self.finished {
+            self.finished = true;
+            self.shared.queue(self.id, FIN, Bytes::new())?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxWriter {
+    fn drop(&mut self) {
+        // dropped before finishing, the reader must not take what it
+        // got for the whole stream
+        if !self.finished {
+            self.shared.queue(self.id, RESET, Bytes::new()).ok();
+        }
+        self.shared.end_writing(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{read_frame, write_frame};
+
+    #[tokio::test]
+    async fn test_streams() -> Result<()> {
+        let (a, b) = tokio::io::duplex(1024);
+        let mut dialer = connection(a, Side::Dialer);
+        let mut listener = connection(b, Side::Listener);
+
+        let (mut receive, mut send) = dialer.opener.open_bidirectional().await?.split();
+        write_frame(&mut send, b"hello").await?;
+        let (mut their_receive, mut their_send) = listener.acceptor.accept_bidirectional().await?.unwrap().split();
+        assert_eq!(read_frame(&mut their_receive).await?.unwrap(), &b"hello"[..]);
+        write_frame(&mut their_send, b"hi").await?;
+        assert_eq!(read_frame(&mut receive).await?.unwrap(), &b"hi"[..]);
+
+        // more than fits in a frame or the pipe, then the end of it
+        let data = vec![7u8; 3 * MAX_PAYLOAD + 5];
+        let mut uni = listener.opener.open_send().await?;
+        let sent = data.clone();
+        tokio::spawn(async move {
+            uni.write_all(&sent).await?;
+            uni.shutdown().await
+        });
+        let mut reader = dialer.acceptor.accept_receive().await?.unwrap();
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await?;
+        assert_eq!(received, data);
+
+        // a writer that goes away unfinished leaves its reader an error
+        let mut uni = listener.opener.open_send().await?;
+        uni.write_all(b"partial").await?;
+        drop(uni);
+        let mut reader = dialer.acceptor.accept_receive().await?.unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).await.is_err());
+
+        // once the reader stops, the writer is told
+        their_receive.stop_sending();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(send.write_all(b"more").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_slow_reader() -> Result<()> {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let mut dialer = connection(a, Side::Dialer);
+        let mut listener = connection(b, Side::Listener);
+
+        // a download nobody reads fills its window and waits
+        let data = vec![3u8; 2 * STREAM_WINDOW];
+        let mut uni = listener.opener.open_send().await?;
+        let sent = data.clone();
+        let download = tokio::spawn(async move {
+            uni.write_all(&sent).await?;
+            uni.shutdown().await
+        });
+        let mut reader = dialer.acceptor.accept_receive().await?.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!download.is_finished());
+
+        // while requests go on as usual
+        let (mut receive, mut send) = dialer.opener.open_bidirectional().await?.split();
+        write_frame(&mut send, b"status").await?;
+        let (mut their_receive, mut their_send) = listener.acceptor.accept_bidirectional().await?.unwrap().split();
+        assert_eq!(read_frame(&mut their_receive).await?.unwrap(), &b"status"[..]);
+        write_frame(&mut their_send, b"ok").await?;
+        assert_eq!(read_frame(&mut receive).await?.unwrap(), &b"ok"[..]);
+
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await?;
+        assert_eq!(received, data);
+        download.await??;
+        Ok(())
+    }
+
+    /// A frame as the peer would write it
+    fn frame(id: u32, kind: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = id.to_be_bytes().to_vec();
+        frame.push(kind);
+        frame.extend((payload.len() as u32).to_be_bytes());
+        frame.extend(payload);
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_peer_breaking_rules() -> Result<()> {
+        let data = vec![0u8; MAX_PAYLOAD];
+        let overrun: Vec<u8> = (0..=STREAM_WINDOW / MAX_PAYLOAD).flat_map(|_| frame(0, DATA, &data)).collect();
+        let cases = [
+            ("send stream from the dialer", frame(0, OPEN_UNI, b"")),
+            ("id of the listener's", frame(1, OPEN_BIDI, b"")),
+            ("id in use", [frame(2, OPEN_BIDI, b""), frame(2, OPEN_BIDI, b"")].concat()),
+            ("id gone back", [frame(4, OPEN_BIDI, b""), frame(2, OPEN_BIDI, b"")].concat()),
+            ("past the window", [frame(0, OPEN_BIDI, b""), overrun].concat()),
+        ];
+        for (case, bytes) in cases {
+            let (mut a, b) = tokio::io::duplex(STREAM_WINDOW * 2);
+            let mut listener = connection(b, Side::Listener);
+            a.write_all(&bytes).await?;
+            // whatever was accepted, the connection ends
+            let mut accepted = Vec::new();
+            let closed = tokio::time::timeout(std::time::Duration::from_secs(1), async {
+                while let Some(stream) = listener.acceptor.accept_bidirectional().await? {
+                    accepted.push(stream);
+                }
+                Ok::<_, anyhow::Error>(())
+            });
+            assert!(closed.await.is_ok(), "{}", case);
+        }
+
+        // while one keeping to them stays connected
+        let (mut a, b) = tokio::io::duplex(1024);
+        let mut listener = connection(b, Side::Listener);
+        a.write_all(&[frame(0, OPEN_BIDI, b""), frame(6, OPEN_BIDI, b""), frame(0, DATA, b"hi")].concat()).await?;
+        let first = listener.acceptor.accept_bidirectional().await?;
+        let second = listener.acceptor.accept_bidirectional().await?;
+        assert!(first.is_some() && second.is_some());
+        let more = tokio::time::timeout(std::time::Duration::from_millis(100), listener.acceptor.accept_bidirectional());
+        assert!(more.await.is_err());
+        Ok(())
+    }
+}